@@ -0,0 +1,545 @@
+//! Hashes local images exactly the way the Lambda function does, printing one
+//! JSON line per result.
+//!
+//! Failures are printed as the function's error objects, on stdout for
+//! images that failed to hash and on stderr for everything else.
+
+use clap::{Args, Parser, Subcommand};
+use image::{DynamicImage, ImageFormat};
+use image_hasher::{HashAlg, ImageHash};
+use lambda_image_hash::compare::{compare_hashes, DEFAULT_THRESHOLD};
+use lambda_image_hash::digest::Digests;
+use lambda_image_hash::hash::{decode_image, hash_image, DecodeLimits};
+use lambda_image_hash::index::BkTree;
+use lambda_image_hash::invariant::{self, Invariance, Transform};
+use lambda_image_hash::lambda::Response;
+use lambda_image_hash::options::{DiffGauss, HashConfig, HashOptions, ResizeFilter};
+use lambda_image_hash::orientation::{read_orientation, Orientation};
+use lambda_image_hash::{ErrorObject, HashSettings, TypedError};
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(version, about)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    hash: HashArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Hash files, globs or directories (the default).
+    Hash(HashArgs),
+    /// Hamming distance between two images.
+    Compare {
+        first: PathBuf,
+        second: PathBuf,
+        /// Distance in bits at or below which the images count as similar.
+        #[arg(long, default_value_t = DEFAULT_THRESHOLD)]
+        threshold: u32,
+        #[command(flatten)]
+        options: OptionArgs,
+    },
+    /// Group near-duplicate images.
+    Dedupe {
+        /// Largest distance in bits between two images of a group.
+        #[arg(long, default_value_t = DEFAULT_THRESHOLD)]
+        max_distance: u32,
+        #[arg(required = true)]
+        paths: Vec<String>,
+        #[command(flatten)]
+        options: OptionArgs,
+    },
+}
+
+#[derive(Args)]
+struct HashArgs {
+    /// Image files, glob patterns, or directories to walk.
+    paths: Vec<String>,
+    #[command(flatten)]
+    options: OptionArgs,
+}
+
+/// The same hasher settings a Lambda request can give.
+#[derive(Args)]
+struct OptionArgs {
+    /// Hash algorithm, repeated to compute several hashes (default Gradient).
+    #[arg(long = "algo", value_parser = parse_serde::<HashAlg>)]
+    algos: Vec<HashAlg>,
+    #[arg(long)]
+    hash_width: Option<u32>,
+    #[arg(long)]
+    hash_height: Option<u32>,
+    #[arg(long, value_parser = parse_serde::<ResizeFilter>)]
+    resize_filter: Option<ResizeFilter>,
+    #[arg(long)]
+    preproc_dct: bool,
+    /// Difference of Gaussians preprocessing with the default sigmas.
+    #[arg(long)]
+    preproc_diff_gauss: bool,
+    /// Hash images as stored, ignoring their EXIF orientation.
+    #[arg(long)]
+    no_auto_orient: bool,
+    /// Hash every rotation and mirroring too, reporting the `canonical`
+    /// smallest hash or `all` of them; `compare` and `dedupe` then measure
+    /// distances from the closest one.
+    #[arg(long, value_parser = parse_serde::<Invariance>)]
+    invariant: Option<Invariance>,
+}
+
+impl OptionArgs {
+    fn settings(&self) -> Result<HashSettings, TypedError> {
+        let mut algos = Vec::with_capacity(self.algos.len());
+        for &algo in &self.algos {
+            if !algos.contains(&algo) {
+                algos.push(algo);
+            }
+        }
+        if algos.is_empty() {
+            algos.push(HashAlg::Gradient);
+        }
+        let options = HashOptions {
+            hash_width: self.hash_width,
+            hash_height: self.hash_height,
+            resize_filter: self.resize_filter,
+            preproc_dct: self.preproc_dct,
+            preproc_diff_gauss: Some(DiffGauss::Enabled(self.preproc_diff_gauss)),
+        };
+        let config = options.resolve(&algos)?;
+        Ok(HashSettings {
+            algos,
+            config,
+            invariance: self.invariant,
+        })
+    }
+}
+
+/// Parses an enum by its variant name, the way it appears in JSON requests.
+fn parse_serde<T: DeserializeOwned>(s: &str) -> Result<T, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_string())).map_err(|e| e.to_string())
+}
+
+/// The function's response for one file, or its error object.
+#[derive(Serialize)]
+struct FileResult {
+    path: PathBuf,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    response: Option<Response>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorObject>,
+}
+
+impl FileResult {
+    fn new(path: PathBuf, result: Result<Response, TypedError>) -> Self {
+        match result {
+            Ok(response) => FileResult {
+                path,
+                response: Some(response),
+                error: None,
+            },
+            Err(error) => FileResult {
+                error: Some(ErrorObject::from(error).or_key(path.display().to_string())),
+                path,
+                response: None,
+            },
+        }
+    }
+}
+
+/// A failure other than that of a single image.
+#[derive(Serialize)]
+struct Failure {
+    error: ErrorObject,
+}
+
+#[derive(Serialize)]
+struct GroupMember {
+    path: PathBuf,
+    hash_base64: String,
+}
+
+#[derive(Serialize)]
+struct Group {
+    files: Vec<GroupMember>,
+}
+
+/// Expands glob patterns and walks directories, each directory's entries in
+/// parallel. Only files in a readable image format are picked up from
+/// directories, while explicitly named files are always kept.
+fn collect_files(args: &[String]) -> Result<Vec<PathBuf>, TypedError> {
+    let mut files = Vec::new();
+    for arg in args {
+        if arg.contains(['*', '?', '[']) {
+            let paths = glob::glob(arg)
+                .map_err(|e| TypedError::InvalidRequest(format!("invalid pattern `{arg}`: {e}")))?;
+            for path in paths {
+                let path = path.map_err(|e| {
+                    let key = e.path().display().to_string();
+                    TypedError::from_io(key, e.into())
+                })?;
+                if path.is_dir() {
+                    files.extend(walk(&path));
+                } else {
+                    files.push(path);
+                }
+            }
+        } else {
+            let path = PathBuf::from(arg);
+            if path.is_dir() {
+                files.extend(walk(&path));
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn walk(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+    entries.sort();
+    entries
+        .into_par_iter()
+        .flat_map(|path| {
+            if path.is_dir() {
+                walk(&path)
+            } else if is_image(&path) {
+                vec![path]
+            } else {
+                Vec::new()
+            }
+        })
+        .collect()
+}
+
+fn is_image(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled())
+}
+
+/// An image file as decoded and turned upright.
+struct DecodedFile {
+    bytes: Vec<u8>,
+    image: DynamicImage,
+    /// The orientation applied, if any.
+    orientation: Option<Orientation>,
+}
+
+/// Reads and decodes an image, turning it upright unless `no_auto_orient`.
+fn decode_file(path: &Path, no_auto_orient: bool) -> Result<DecodedFile, TypedError> {
+    let bytes =
+        std::fs::read(path).map_err(|e| TypedError::from_io(path.display().to_string(), e))?;
+    let mut image = decode_image(&bytes, &DecodeLimits::default())?;
+    let orientation = read_orientation(&bytes).filter(|_| !no_auto_orient);
+    if let Some(orientation) = orientation {
+        image = orientation.apply(image);
+    }
+    Ok(DecodedFile {
+        bytes,
+        image,
+        orientation,
+    })
+}
+
+/// Hashes a file into the response the function gives for an inline image.
+fn hash_file(
+    path: &Path,
+    options: &OptionArgs,
+    settings: &HashSettings,
+) -> Result<Response, TypedError> {
+    let file = decode_file(path, options.no_auto_orient)?;
+    let mut response = Response::from(hash_image(&file.image, settings));
+    response.orientation = file.orientation;
+    response.digests = Some(Digests::new(&file.bytes, &file.image));
+    Ok(response)
+}
+
+/// The primary hash of an image, first, and with invariance that of every
+/// other transform of it.
+fn hash_raw(
+    path: &Path,
+    options: &OptionArgs,
+    settings: &HashSettings,
+) -> Result<Vec<(Transform, ImageHash)>, TypedError> {
+    let img = decode_file(path, options.no_auto_orient)?.image;
+    let algo = settings.algos[0];
+    let hasher = settings.config.hasher_config(algo).to_hasher();
+    Ok(match settings.invariance {
+        Some(_) => invariant::transform_hashes(&img, &hasher),
+        None => vec![(Transform::Identity, hasher.hash_image(&img))],
+    })
+}
+
+fn print_json(value: &impl Serialize) {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer(&mut stdout, value).expect("failed to write to stdout");
+    writeln!(stdout).expect("failed to write to stdout");
+}
+
+fn eprint_json(value: &impl Serialize) {
+    let mut stderr = std::io::stderr().lock();
+    serde_json::to_writer(&mut stderr, value).expect("failed to write to stderr");
+    writeln!(stderr).expect("failed to write to stderr");
+}
+
+fn run_hash(args: HashArgs) -> Result<bool, TypedError> {
+    let settings = args.options.settings()?;
+    let files = collect_files(&args.paths)?;
+    let results: Vec<FileResult> = files
+        .into_par_iter()
+        .map(|path| {
+            let result = hash_file(&path, &args.options, &settings);
+            FileResult::new(path, result)
+        })
+        .collect();
+    let mut ok = true;
+    for result in &results {
+        ok &= result.error.is_none();
+        print_json(result);
+    }
+    Ok(ok)
+}
+
+fn run_compare(
+    first: &Path,
+    second: &Path,
+    threshold: u32,
+    options: &OptionArgs,
+) -> Result<bool, TypedError> {
+    let settings = options.settings()?;
+    let (hashes, other_hashes) = rayon::join(
+        || hash_raw(first, options, &settings),
+        || hash_raw(second, options, &settings),
+    );
+    let (hashes, other_hash) = (hashes?, &other_hashes?[0].1);
+    let ((transform, hash), _) = invariant::closest(&hashes, other_hash);
+    let mut response = compare_hashes(
+        hash,
+        other_hash,
+        settings.algos[0],
+        settings.config,
+        threshold,
+    )?;
+    response.transform = settings.invariance.map(|_| *transform);
+    print_json(&response);
+    Ok(true)
+}
+
+/// Prints every group of images connected by distances of at most
+/// `max_distance`, largest group first. Images that fail to hash are
+/// reported on stderr.
+fn run_dedupe(
+    paths: &[String],
+    max_distance: u32,
+    options: &OptionArgs,
+) -> Result<bool, TypedError> {
+    let settings = options.settings()?;
+    let files = collect_files(paths)?;
+    let hashes: Vec<Result<Vec<(Transform, ImageHash)>, TypedError>> = files
+        .par_iter()
+        .map(|path| hash_raw(path, options, &settings))
+        .collect();
+
+    let mut ok = true;
+    let mut hashed = Vec::new();
+    for (path, hash) in files.into_iter().zip(hashes) {
+        match hash {
+            Ok(hashes) => hashed.push((path, hashes)),
+            Err(error) => {
+                ok = false;
+                eprint_json(&FileResult::new(path, Err(error)));
+            }
+        }
+    }
+
+    let images: Vec<_> = hashed.iter().map(|(_, hashes)| hashes.as_slice()).collect();
+    for group in group(&images, settings.algos[0], &settings.config, max_distance) {
+        print_json(&Group {
+            files: group
+                .into_iter()
+                .map(|i| GroupMember {
+                    path: hashed[i].0.clone(),
+                    hash_base64: hashed[i].1[0].1.to_base64(),
+                })
+                .collect(),
+        });
+    }
+    Ok(ok)
+}
+
+/// The indexes of `images` in groups connected by distances of at most
+/// `max_distance`, largest group first, leaving out images on their own.
+/// Each image is given as the hashes of its transforms, untransformed first.
+fn group(
+    images: &[&[(Transform, ImageHash)]],
+    algo: HashAlg,
+    config: &HashConfig,
+    max_distance: u32,
+) -> Vec<Vec<usize>> {
+    let mut tree = BkTree::new(algo, config.clone());
+    for (i, hashes) in images.iter().enumerate() {
+        tree.insert_new(&i.to_string(), &hashes[0].1);
+    }
+
+    // union-find over the images, joining each one with its neighbours
+    let mut parents: Vec<usize> = (0..images.len()).collect();
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+    for (i, hashes) in images.iter().enumerate() {
+        for found in tree.find_closest(hashes, max_distance) {
+            let j: usize = found.key.parse().expect("keys are file indices");
+            let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+            parents[a] = b;
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = vec![Vec::new(); images.len()];
+    for i in 0..images.len() {
+        let r = root(&mut parents, i);
+        groups[r].push(i);
+    }
+    groups.retain(|group| group.len() > 1);
+    groups.sort_by_key(|group| std::cmp::Reverse(group.len()));
+    groups
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        None => run_hash(cli.hash),
+        Some(Command::Hash(args)) => run_hash(args),
+        Some(Command::Compare {
+            first,
+            second,
+            threshold,
+            options,
+        }) => run_compare(&first, &second, threshold, &options),
+        Some(Command::Dedupe {
+            max_distance,
+            paths,
+            options,
+        }) => run_dedupe(&paths, max_distance, &options),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(error) => {
+            eprint_json(&Failure {
+                error: ErrorObject::from(error),
+            });
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use std::fs;
+
+    /// A fresh directory for a test's files.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("image-hash-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn directories_are_walked_and_globs_expanded() {
+        let dir = temp_dir("walk");
+        fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+        for file in [
+            "a.jpg",
+            "b.webp",
+            "notes.txt",
+            "sub/c.jpg",
+            "sub/deeper/d.webp",
+        ] {
+            fs::write(dir.join(file), b"").unwrap();
+        }
+        let arg = |path: &str| dir.join(path).display().to_string();
+
+        // only images are picked up from directories, in order.
+        let files = collect_files(&[arg("")]).unwrap();
+        let expected = ["a.jpg", "b.webp", "sub/c.jpg", "sub/deeper/d.webp"];
+        assert_eq!(files, expected.map(|file| dir.join(file)));
+        // globs match files, and directories that are walked in turn.
+        let files = collect_files(&[arg("*.jpg"), arg("su?")]).unwrap();
+        let expected = ["a.jpg", "sub/c.jpg", "sub/deeper/d.webp"];
+        assert_eq!(files, expected.map(|file| dir.join(file)));
+        // files named explicitly are kept whatever they are.
+        let files = collect_files(&[arg("notes.txt"), arg("missing.jpg")]).unwrap();
+        assert_eq!(files, [dir.join("notes.txt"), dir.join("missing.jpg")]);
+
+        let err = collect_files(&[arg("[")]).unwrap_err();
+        assert_eq!(err.code(), "invalid_request");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn hashes(bytes: [u8; 8]) -> Vec<(Transform, ImageHash)> {
+        vec![(Transform::Identity, ImageHash::from_bytes(&bytes).unwrap())]
+    }
+
+    #[test]
+    fn groups_join_images_through_their_neighbours() {
+        let images = [
+            hashes([0; 8]),
+            hashes([0xFF; 8]),
+            hashes([0b1, 0, 0, 0, 0, 0, 0, 0]),
+            hashes([0x0F; 8]),
+            // 3 bits from the first image, but only 2 from the third.
+            hashes([0b111, 0, 0, 0, 0, 0, 0, 0]),
+            hashes([0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFC]),
+        ];
+        let images: Vec<_> = images.iter().map(Vec::as_slice).collect();
+        let config = HashConfig::default();
+
+        let groups = group(&images, HashAlg::Gradient, &config, 2);
+        assert_eq!(groups, [vec![0, 2, 4], vec![1, 5]]);
+        let groups = group(&images, HashAlg::Gradient, &config, 1);
+        assert_eq!(groups, [vec![0, 2]]);
+        assert!(group(&images, HashAlg::Gradient, &config, 0).is_empty());
+    }
+
+    #[test]
+    fn results_have_the_shape_of_the_function_response() {
+        let dir = temp_dir("shape");
+        let img = RgbImage::from_fn(32, 24, |x, y| image::Rgb([x as u8 * 8, y as u8 * 10, 90]));
+        let path = dir.join("cat.jpg");
+        DynamicImage::ImageRgb8(img).save(&path).unwrap();
+        let options = Cli::parse_from(["image-hash"]).hash.options;
+        let settings = options.settings().unwrap();
+
+        let result = FileResult::new(path.clone(), hash_file(&path, &options, &settings));
+        let json = serde_json::to_value(result).unwrap();
+        assert_eq!(json["path"], path.display().to_string());
+        assert_eq!(json["algo"], "Gradient");
+        assert!(json["hash_base64"].is_string());
+        assert!(json["digests"]["file"]["sha256"].is_string());
+        assert!(json.get("error").is_none());
+
+        let missing = dir.join("missing.jpg");
+        let result = FileResult::new(missing.clone(), hash_file(&missing, &options, &settings));
+        let json = serde_json::to_value(result).unwrap();
+        assert_eq!(json["error"]["code"], "not_found");
+        assert_eq!(json["error"]["key"], missing.display().to_string());
+        assert!(json.get("hash_base64").is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+//! Results of hashing S3 objects, kept so that unchanged objects aren't
+//! downloaded and hashed again.
+//!
+//! Results are keyed by the object's location and version, as a `HeadObject`
+//! request reports them, and by every part of the request that changes the
+//! result. They are kept in memory across warm invocations, and in S3 to
+//! share them between instances and keep them over cold starts.
+
+use crate::digest::Digests;
+use crate::frames::FrameHashes;
+use crate::hash::HashResult;
+use crate::orientation::Orientation;
+use crate::s3::{ObjectVersion, S3Location};
+use aws_sdk_s3::primitives::ByteStream;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::num::NonZeroUsize;
+use tokio::sync::Mutex;
+
+/// The version of the cached results, part of every key.
+///
+/// Bump it whenever a change makes results cached before it wrong, such as
+/// hashing or decoding images differently, or unreadable, such as changing
+/// the fields of [`CachedResult`]. Releases that keep it share their cache.
+pub const CACHE_VERSION: u32 = 1;
+
+/// Where results are cached.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Most results kept in memory, or none if 0.
+    pub entries: usize,
+    /// The bucket results are stored in, and the prefix of their keys.
+    pub location: Option<S3Location>,
+}
+
+/// The part of a response that only depends on the object and the request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResult {
+    pub hash: HashResult,
+    pub orientation: Option<Orientation>,
+    pub digests: Option<Digests>,
+    pub frames: Option<FrameHashes>,
+}
+
+/// What a result is cached under: the hex SHA-256 of the object's location
+/// and version, and of the request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    /// The key of the result of `request` for `version` of the object at
+    /// `location`. `None` if the version has neither an ETag, a version id
+    /// nor a modification time, so changes to the object can't be told.
+    ///
+    /// Results cached under another [`CACHE_VERSION`] never match.
+    pub fn new(location: &S3Location, version: &ObjectVersion, request: &Value) -> Option<Self> {
+        if version.e_tag.is_none()
+            && version.version_id.is_none()
+            && version.last_modified.is_none()
+        {
+            return None;
+        }
+        let key = json!({
+            "version": CACHE_VERSION,
+            "location": location.to_string(),
+            "e_tag": version.e_tag,
+            "version_id": version.version_id,
+            "last_modified": version
+                .last_modified
+                .map(|time| (time.secs(), time.subsec_nanos())),
+            "request": request,
+        });
+        Some(CacheKey(hex::encode(Sha256::digest(key.to_string()))))
+    }
+}
+
+/// Cached results, in memory, in S3, or both.
+pub struct ResultCache {
+    memory: Option<Mutex<LruCache<CacheKey, CachedResult>>>,
+    location: Option<S3Location>,
+}
+
+impl ResultCache {
+    /// `None` if `config` keeps results nowhere.
+    pub fn new(config: &CacheConfig) -> Option<Self> {
+        let memory =
+            NonZeroUsize::new(config.entries).map(|entries| Mutex::new(LruCache::new(entries)));
+        if memory.is_none() && config.location.is_none() {
+            return None;
+        }
+        Some(ResultCache {
+            memory,
+            location: config.location.clone(),
+        })
+    }
+
+    /// The result cached under `key`, from memory or else from S3. Failing
+    /// to read it from S3 is logged and treated as a miss.
+    pub async fn get(
+        &self,
+        s3_client: &aws_sdk_s3::Client,
+        key: &CacheKey,
+    ) -> Option<CachedResult> {
+        if let Some(memory) = &self.memory {
+            if let Some(result) = memory.lock().await.get(key) {
+                return Some(result.clone());
+            }
+        }
+        let location = self.object(key)?;
+        let output = s3_client
+            .get_object()
+            .bucket(&location.bucket)
+            .key(&location.key)
+            .send()
+            .await;
+        let data = match output {
+            Ok(output) => output.body.collect().await.map_err(|e| e.to_string()),
+            Err(err) if err.as_service_error().is_some_and(|e| e.is_no_such_key()) => return None,
+            Err(err) => Err(err.to_string()),
+        };
+        let result = data.and_then(|data| {
+            serde_json::from_slice::<CachedResult>(&data.into_bytes()).map_err(|e| e.to_string())
+        });
+        match result {
+            Ok(result) => {
+                if let Some(memory) = &self.memory {
+                    memory.lock().await.put(key.clone(), result.clone());
+                }
+                Some(result)
+            }
+            Err(err) => {
+                tracing::warn!(
+                    err = %err,
+                    bucket = %location.bucket,
+                    key = %location.key,
+                    "failed to read a cached result from S3"
+                );
+                None
+            }
+        }
+    }
+
+    /// Caches `result` under `key`. Failing to store it in S3 is logged, as
+    /// the result itself is still good.
+    pub async fn put(&self, s3_client: &aws_sdk_s3::Client, key: CacheKey, result: CachedResult) {
+        if let Some(location) = self.object(&key) {
+            let body = serde_json::to_vec(&result).expect("cached results always serialize");
+            let output = s3_client
+                .put_object()
+                .bucket(&location.bucket)
+                .key(&location.key)
+                .content_type("application/json")
+                .body(ByteStream::from(body))
+                .send()
+                .await;
+            if let Err(err) = output {
+                tracing::warn!(
+                    err = %err,
+                    bucket = %location.bucket,
+                    key = %location.key,
+                    "failed to store a cached result in S3"
+                );
+            }
+        }
+        if let Some(memory) = &self.memory {
+            memory.lock().await.put(key, result);
+        }
+    }
+
+    /// Where the result under `key` is stored in S3, if it is.
+    fn object(&self, key: &CacheKey) -> Option<S3Location> {
+        self.location.as_ref().map(|location| S3Location {
+            bucket: location.bucket.clone(),
+            key: format!("{}{}.json", location.key, key.0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location() -> S3Location {
+        S3Location {
+            bucket: "photos".to_string(),
+            key: "cat.jpg".to_string(),
+        }
+    }
+
+    fn version(e_tag: &str) -> ObjectVersion {
+        ObjectVersion {
+            e_tag: Some(e_tag.to_string()),
+            ..ObjectVersion::default()
+        }
+    }
+
+    #[test]
+    fn keys_change_with_the_object_and_the_request() {
+        let request = json!({"algos": ["Gradient"]});
+        let key = CacheKey::new(&location(), &version("\"a\""), &request).unwrap();
+        assert_eq!(
+            CacheKey::new(&location(), &version("\"a\""), &request),
+            Some(key.clone())
+        );
+        assert_eq!(key.0.len(), 64);
+        assert_ne!(
+            CacheKey::new(&location(), &version("\"b\""), &request),
+            Some(key.clone())
+        );
+        let other = json!({"algos": ["Mean"]});
+        assert_ne!(
+            CacheKey::new(&location(), &version("\"a\""), &other),
+            Some(key)
+        );
+        assert_eq!(
+            CacheKey::new(&location(), &ObjectVersion::default(), &request),
+            None
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Hamming distance between the hashes of two images.
+
+use crate::invariant::{self, Invariance, Transform};
+use crate::lambda::State;
+use crate::metrics;
+use crate::options::{HashConfig, HashOptions};
+use crate::s3::S3Location;
+use crate::source::fetch_image;
+use crate::TypedError;
+use image_hasher::{HashAlg, ImageHash};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// Distance in bits at or below which two images count as similar, when the
+/// request gives no `threshold`.
+pub const DEFAULT_THRESHOLD: u32 = 10;
+
+/// Compares an image against either a second image or an already known hash.
+#[derive(Deserialize)]
+pub struct CompareRequest {
+    /// Either an object key, or a full `s3://bucket/key` URI.
+    pub path: String,
+    pub bucket: Option<String>,
+    /// The second image, resolved like `path`.
+    pub other_path: Option<String>,
+    /// Bucket of the second image, defaulting to `bucket`.
+    pub other_bucket: Option<String>,
+    /// A base64 hash to compare against instead of `other_path`.
+    pub other_hash: Option<String>,
+    pub algo: Option<HashAlg>,
+    pub threshold: Option<u32>,
+    #[serde(flatten)]
+    pub options: HashOptions,
+    /// Turn both images upright as their EXIF orientation says before
+    /// hashing, unless `false`.
+    pub auto_orient: Option<bool>,
+    /// With either invariance, measure the distance from the closest
+    /// rotation or mirroring of the first image.
+    pub invariant: Option<Invariance>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompareResponse {
+    pub hash_base64: String,
+    pub other_hash_base64: String,
+    pub algo: HashAlg,
+    pub config: HashConfig,
+    /// Number of differing bits.
+    pub distance: u32,
+    /// `1 - distance / bits`, from 0 for opposite hashes to 1 for equal ones.
+    pub similarity: f64,
+    pub threshold: u32,
+    /// Whether `distance` is at most `threshold`.
+    pub similar: bool,
+    /// With `invariant`, the transform of the first image closest to the
+    /// other one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Transform>,
+}
+
+pub async fn compare(
+    state: &State,
+    request: CompareRequest,
+    deadline: Option<Instant>,
+) -> Result<CompareResponse, TypedError> {
+    tracing::info!("handling a compare request");
+    let start = Instant::now();
+
+    let algo = request.algo.unwrap_or(HashAlg::Gradient);
+    let hash_config = request.options.resolve(&[algo])?;
+    let hasher = hash_config.hasher_config(algo).to_hasher();
+    let default_bucket = state.config.default_bucket.as_deref();
+    let limits = &state.config.limits;
+    let auto_orient = request.auto_orient.unwrap_or(true);
+    let location = S3Location::resolve(&request.path, request.bucket.as_deref(), default_bucket)?;
+    let hash_first = |img| match request.invariant {
+        Some(_) => invariant::transform_hashes(img, &hasher),
+        None => vec![(Transform::Identity, hasher.hash_image(img))],
+    };
+
+    let (hashes, other_hash) = match (&request.other_path, &request.other_hash) {
+        (Some(other_path), None) => {
+            let other_location = S3Location::resolve(
+                other_path,
+                request
+                    .other_bucket
+                    .as_deref()
+                    .or(request.bucket.as_deref()),
+                default_bucket,
+            )?;
+            let (mut fetched, mut other_fetched) = futures::try_join!(
+                fetch_image(state.source.as_ref(), &location, limits, deadline),
+                fetch_image(state.source.as_ref(), &other_location, limits, deadline),
+            )?;
+            fetched.auto_orient(auto_orient);
+            other_fetched.auto_orient(auto_orient);
+            (
+                hash_first(&fetched.image),
+                hasher.hash_image(&other_fetched.image),
+            )
+        }
+        (None, Some(other_hash)) => {
+            let other_hash = ImageHash::<Box<[u8]>>::from_base64(other_hash)
+                .map_err(|e| TypedError::InvalidRequest(format!("invalid `other_hash`: {e:?}")))?;
+            let mut fetched =
+                fetch_image(state.source.as_ref(), &location, limits, deadline).await?;
+            fetched.auto_orient(auto_orient);
+            (hash_first(&fetched.image), other_hash)
+        }
+        _ => {
+            return Err(TypedError::InvalidRequest(
+                "exactly one of `other_path` and `other_hash` must be given".to_string(),
+            ))
+        }
+    };
+
+    let ((transform, hash), _) = invariant::closest(&hashes, &other_hash);
+    let mut response = compare_hashes(
+        hash,
+        &other_hash,
+        algo,
+        hash_config,
+        request.threshold.unwrap_or(DEFAULT_THRESHOLD),
+    )?;
+    response.transform = request.invariant.map(|_| *transform);
+    metrics::images_compared(algo, response.similar, start.elapsed());
+    Ok(response)
+}
+
+/// Compares two hashes computed with `algo` and `config`.
+pub fn compare_hashes(
+    hash: &ImageHash,
+    other_hash: &ImageHash,
+    algo: HashAlg,
+    config: HashConfig,
+    threshold: u32,
+) -> Result<CompareResponse, TypedError> {
+    if hash.as_bytes().len() != other_hash.as_bytes().len() {
+        return Err(TypedError::InvalidRequest(format!(
+            "`other_hash` has {} bytes but {algo:?} with this config produces {}",
+            other_hash.as_bytes().len(),
+            hash.as_bytes().len(),
+        )));
+    }
+
+    let distance = hash.dist(other_hash);
+    let bits = config.hash_bits(algo);
+    Ok(CompareResponse {
+        hash_base64: hash.to_base64(),
+        other_hash_base64: other_hash.to_base64(),
+        algo,
+        config,
+        distance,
+        similarity: 1.0 - f64::from(distance) / f64::from(bits),
+        threshold,
+        similar: distance <= threshold,
+        transform: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similarity_ignores_padding_bits() {
+        // 5x5 hashes have 25 bits, padded to 4 bytes.
+        let config = HashOptions {
+            hash_width: Some(5),
+            hash_height: Some(5),
+            ..HashOptions::default()
+        }
+        .resolve(&[HashAlg::Gradient])
+        .unwrap();
+        let hash = ImageHash::from_bytes(&[0; 4]).unwrap();
+        let opposite = ImageHash::from_bytes(&[0xFF, 0xFF, 0xFF, 0x01]).unwrap();
+
+        let response = compare_hashes(&hash, &opposite, HashAlg::Gradient, config, 10).unwrap();
+        assert_eq!(response.distance, 25);
+        assert_eq!(response.similarity, 0.0);
+        assert!(!response.similar);
+    }
+
+    #[test]
+    fn invariant_takes_the_request_values() {
+        let request: CompareRequest = serde_json::from_value(serde_json::json!({
+            "path": "cat.jpg",
+            "other_path": "dog.jpg",
+            "invariant": "canonical",
+        }))
+        .unwrap();
+        assert_eq!(request.invariant, Some(Invariance::Canonical));
+        let request: Result<CompareRequest, _> = serde_json::from_value(serde_json::json!({
+            "path": "cat.jpg",
+            "other_path": "dog.jpg",
+            "invariant": true,
+        }));
+        assert!(request.is_err());
+    }
+}
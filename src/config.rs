@@ -0,0 +1,49 @@
+//! Reading settings from environment variables, once per cold start.
+//!
+//! Settings that are set but don't parse panic, so a misconfigured function
+//! fails on its first invocation instead of running with a default nobody
+//! asked for.
+
+use std::fmt::Display;
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Parses the environment variable `name` with `parse`, or returns `None` if
+/// unset.
+///
+/// # Panics
+///
+/// If the variable is set but `parse` fails, saying that it must be `what`.
+pub fn env_parse<T, E: Display>(
+    name: &str,
+    what: &str,
+    parse: impl FnOnce(&str) -> Result<T, E>,
+) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match parse(&value) {
+        Ok(parsed) => Some(parsed),
+        Err(err) => panic!("{name} must be {what}, got `{value}`: {err}"),
+    }
+}
+
+/// Parses the integer environment variable `name`, or returns `default` if
+/// unset.
+///
+/// # Panics
+///
+/// If the variable is set to something that isn't an integer of type `T`.
+pub fn env_or<T: FromStr<Err = ParseIntError>>(name: &str, default: T) -> T {
+    env_parse(name, "an integer", str::parse).unwrap_or(default)
+}
+
+/// Reads the environment variable `name` as a number of milliseconds, or
+/// returns `default` if unset.
+///
+/// # Panics
+///
+/// If the variable is set to something that isn't a number of milliseconds.
+pub fn env_millis(name: &str, default: Duration) -> Duration {
+    env_parse(name, "a number of milliseconds", str::parse::<u64>)
+        .map_or(default, Duration::from_millis)
+}
@@ -0,0 +1,95 @@
+//! Cryptographic digests of images, for finding exact duplicates next to
+//! the near-duplicates perceptual hashes find.
+//!
+//! The encoded bytes are digested to find copies of the same file, and the
+//! decoded pixels to find the same picture saved in another format or with
+//! other metadata.
+
+use image::{DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Hex digests of one byte stream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentDigest {
+    pub sha256: String,
+    /// Only computed with the `blake3` feature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+}
+
+/// Digests of an encoded image and of its pixels.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Digests {
+    /// Of the encoded bytes, exactly as stored.
+    pub file: ContentDigest,
+    /// Of the width and height as big-endian `u32`s, followed by the pixels
+    /// as 8-bit RGBA, row by row.
+    pub pixels: ContentDigest,
+}
+
+impl Digests {
+    /// Digests `bytes`, and `img` as decoded from them and turned upright.
+    pub fn new(bytes: &[u8], img: &DynamicImage) -> Self {
+        let mut file = Hashers::default();
+        file.update(bytes);
+
+        let mut pixels = Hashers::default();
+        let (width, height) = img.dimensions();
+        pixels.update(&width.to_be_bytes());
+        pixels.update(&height.to_be_bytes());
+        // other layouts are converted a row at a time, so that a large image
+        // isn't held twice.
+        let mut row = Vec::with_capacity(width as usize * 4);
+        match img {
+            DynamicImage::ImageRgba8(buffer) => pixels.update(buffer.as_raw()),
+            DynamicImage::ImageRgb8(buffer) => {
+                for rgb in buffer.as_raw().chunks_exact((width as usize * 3).max(1)) {
+                    row.clear();
+                    row.extend(
+                        rgb.chunks_exact(3)
+                            .flat_map(|p| [p[0], p[1], p[2], u8::MAX]),
+                    );
+                    pixels.update(&row);
+                }
+            }
+            img => {
+                for y in 0..height {
+                    row.clear();
+                    row.extend((0..width).flat_map(|x| img.get_pixel(x, y).0));
+                    pixels.update(&row);
+                }
+            }
+        }
+        Digests {
+            file: file.finish(),
+            pixels: pixels.finish(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Hashers {
+    sha256: Sha256,
+    #[cfg(feature = "blake3")]
+    blake3: blake3::Hasher,
+}
+
+impl Hashers {
+    fn update(&mut self, bytes: &[u8]) {
+        self.sha256.update(bytes);
+        #[cfg(feature = "blake3")]
+        self.blake3.update(bytes);
+    }
+
+    fn finish(self) -> ContentDigest {
+        #[cfg(feature = "blake3")]
+        let blake3 = Some(self.blake3.finalize().to_hex().to_string());
+        #[cfg(not(feature = "blake3"))]
+        let blake3 = None;
+        ContentDigest {
+            sha256: hex::encode(self.sha256.finalize()),
+            blake3,
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! The errors surfaced to callers.
+
+use image::ImageFormat;
+use lambda_runtime::Diagnostic;
+use serde::Serialize;
+use std::error::Error as _;
+use thiserror::Error;
+
+/// An underlying error kept as the source of a [`TypedError`].
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Error, Debug)]
+pub enum TypedError {
+    #[error("Failed to retrieve `{0}` from S3")]
+    S3Get(String, #[source] BoxError),
+    #[error("Access to `{0}` was denied")]
+    S3AccessDenied(String, #[source] BoxError),
+    #[error("S3 throttled the request for `{0}`")]
+    S3Throttled(String, #[source] BoxError),
+    #[error("Timed out waiting for S3 on `{0}`")]
+    S3Timeout(String, #[source] BoxError),
+    #[error("S3 was unavailable for `{0}`")]
+    S3Unavailable(String, #[source] BoxError),
+    #[error("Failed to download `{0}` from S3")]
+    S3Download(String, #[source] BoxError),
+    #[error(
+        "Invalid image format `{0}` guessed, supported formats are {}",
+        supported_formats()
+    )]
+    InvalidFormat(String),
+    #[error("Invalid S3 path `{0}`")]
+    InvalidPath(String),
+    #[error("No bucket given for `{0}` and no BUCKET_NAME default is set")]
+    MissingBucket(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Failed to write `{0}` to S3")]
+    S3Put(String, #[source] BoxError),
+    #[error("Invalid hash index: `{0}`")]
+    InvalidIndex(String),
+    /// The source is the store's own error, except for in-memory sources.
+    #[error("Object `{0}` not found")]
+    NotFound(String, #[source] Option<BoxError>),
+    #[error("Failed to read `{0}`")]
+    Read(String, #[source] std::io::Error),
+    #[error("Invalid URL `{0}`, only absolute http and https URLs are supported")]
+    InvalidUrl(String),
+    #[error("Host of `{0}` is not in URL_ALLOWED_HOSTS")]
+    UrlNotAllowed(String),
+    #[error("Failed to fetch `{0}`")]
+    UrlFetch(String, #[source] BoxError),
+    #[error("Fetching `{0}` returned HTTP {1}")]
+    UrlStatus(String, u16),
+    #[error("Timed out fetching `{0}`")]
+    UrlTimeout(String),
+    #[error("Too many redirects fetching `{0}`")]
+    TooManyRedirects(String),
+    #[error("`{0}` is larger than the maximum of {1} bytes")]
+    UrlTooLarge(String, usize),
+    #[error("`{0}` is not an image, its content type is `{1}`")]
+    NotAnImage(String, String),
+    #[error("Image of {0}x{1} pixels is over the decode limits")]
+    ImageTooLarge(u32, u32),
+    /// The size is as reported, or as much as was read before giving up.
+    #[error("`{0}` is at least {1} bytes, over the maximum of {2}")]
+    ObjectTooLarge(String, u64, u64),
+}
+
+impl TypedError {
+    /// A stable identifier of the kind of error, for callers to branch on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TypedError::S3Get(_, _) => "s3_get_failed",
+            TypedError::S3AccessDenied(_, _) => "access_denied",
+            TypedError::S3Throttled(_, _) => "throttled",
+            TypedError::S3Timeout(_, _) => "s3_timeout",
+            TypedError::S3Unavailable(_, _) => "s3_unavailable",
+            TypedError::S3Download(_, _) => "s3_download_failed",
+            TypedError::InvalidFormat(_) => "invalid_format",
+            TypedError::InvalidPath(_) => "invalid_path",
+            TypedError::MissingBucket(_) => "missing_bucket",
+            TypedError::InvalidRequest(_) => "invalid_request",
+            TypedError::S3Put(_, _) => "s3_put_failed",
+            TypedError::InvalidIndex(_) => "invalid_index",
+            TypedError::NotFound(_, _) => "not_found",
+            TypedError::Read(_, _) => "read_failed",
+            TypedError::InvalidUrl(_) => "invalid_url",
+            TypedError::UrlNotAllowed(_) => "url_not_allowed",
+            TypedError::UrlFetch(_, _) => "url_fetch_failed",
+            TypedError::UrlStatus(_, _) => "url_status",
+            TypedError::UrlTimeout(_) => "url_timeout",
+            TypedError::TooManyRedirects(_) => "too_many_redirects",
+            TypedError::UrlTooLarge(_, _) => "url_too_large",
+            TypedError::NotAnImage(_, _) => "not_an_image",
+            TypedError::ImageTooLarge(_, _) => "image_too_large",
+            TypedError::ObjectTooLarge(_, _, _) => "object_too_large",
+        }
+    }
+
+    /// Whether the same request may succeed when tried again later.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TypedError::S3Throttled(_, _)
+            | TypedError::S3Timeout(_, _)
+            | TypedError::S3Unavailable(_, _)
+            | TypedError::S3Download(_, _)
+            | TypedError::UrlFetch(_, _)
+            | TypedError::UrlTimeout(_) => true,
+            TypedError::UrlStatus(_, status) => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// A failure to read the file behind `key`, telling missing files apart.
+    pub fn from_io(key: impl Into<String>, err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => TypedError::NotFound(key.into(), Some(Box::new(err))),
+            _ => TypedError::Read(key.into(), err),
+        }
+    }
+
+    /// The S3 URI, path or URL the error is about, if it is about one.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            TypedError::S3Get(key, _)
+            | TypedError::S3AccessDenied(key, _)
+            | TypedError::S3Throttled(key, _)
+            | TypedError::S3Timeout(key, _)
+            | TypedError::S3Unavailable(key, _)
+            | TypedError::S3Download(key, _)
+            | TypedError::InvalidPath(key)
+            | TypedError::MissingBucket(key)
+            | TypedError::S3Put(key, _)
+            | TypedError::NotFound(key, _)
+            | TypedError::Read(key, _)
+            | TypedError::InvalidUrl(key)
+            | TypedError::UrlNotAllowed(key)
+            | TypedError::UrlFetch(key, _)
+            | TypedError::UrlStatus(key, _)
+            | TypedError::UrlTimeout(key)
+            | TypedError::TooManyRedirects(key)
+            | TypedError::UrlTooLarge(key, _)
+            | TypedError::NotAnImage(key, _)
+            | TypedError::ObjectTooLarge(key, _, _) => Some(key),
+            TypedError::InvalidFormat(_)
+            | TypedError::InvalidRequest(_)
+            | TypedError::InvalidIndex(_)
+            | TypedError::ImageTooLarge(_, _) => None,
+        }
+    }
+}
+
+/// The machine-readable form of a [`TypedError`] returned to callers.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorObject {
+    /// See [`TypedError::code`].
+    pub code: &'static str,
+    /// The error followed by each of its sources, separated by `: `.
+    pub message: String,
+    pub retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl ErrorObject {
+    /// Fills in `key` for errors that don't carry one themselves.
+    pub fn or_key(mut self, key: impl Into<String>) -> Self {
+        self.key.get_or_insert_with(|| key.into());
+        self
+    }
+}
+
+impl From<&TypedError> for ErrorObject {
+    fn from(err: &TypedError) -> Self {
+        let mut message = err.to_string();
+        let mut source = err.source();
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        ErrorObject {
+            code: err.code(),
+            message,
+            retryable: err.is_retryable(),
+            key: err.key().map(str::to_string),
+        }
+    }
+}
+
+impl From<TypedError> for ErrorObject {
+    fn from(err: TypedError) -> Self {
+        ErrorObject::from(&err)
+    }
+}
+
+/// Reports failed invocations with the code as the Lambda error type and the
+/// whole object as JSON in the error message.
+impl<'a> From<ErrorObject> for Diagnostic<'a> {
+    fn from(err: ErrorObject) -> Self {
+        Diagnostic {
+            error_type: err.code.into(),
+            error_message: serde_json::to_string(&err)
+                .expect("error objects always serialize")
+                .into(),
+        }
+    }
+}
+
+/// The image formats this build can decode, as enabled by cargo features.
+pub fn supported_formats() -> String {
+    ImageFormat::all()
+        .filter(|format| format.reading_enabled())
+        .map(|format| format!("{format:?}").to_lowercase())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn io_errors_are_kept_as_sources() {
+        let err = TypedError::from_io("a.jpg", io::Error::other("disk on fire"));
+        assert!(err.source().is_some());
+        let object = ErrorObject::from(err);
+        assert_eq!(object.code, "read_failed");
+        assert_eq!(object.message, "Failed to read `a.jpg`: disk on fire");
+
+        let err = TypedError::from_io("b.jpg", io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(err.code(), "not_found");
+        assert!(err.source().is_some());
+        assert_eq!(err.key(), Some("b.jpg"));
+    }
+}
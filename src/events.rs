@@ -0,0 +1,132 @@
+//! Event shapes delivered by other AWS services when they trigger the function.
+
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// An S3 event notification, as sent for `s3:ObjectCreated:*` triggers.
+///
+/// Only the fields needed to locate the object are kept.
+#[derive(Debug, Deserialize)]
+pub struct S3Event {
+    #[serde(rename = "Records")]
+    pub records: Vec<S3EventRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct S3EventRecord {
+    pub event_name: Option<String>,
+    pub s3: S3Entity,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Entity {
+    pub bucket: S3Bucket,
+    pub object: S3Object,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Bucket {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Object {
+    /// The object key, URL-encoded the way S3 sends it.
+    pub key: String,
+}
+
+impl S3Object {
+    /// Decodes the key, where S3 encodes spaces as `+` and everything else as
+    /// `%XX` escapes.
+    pub fn decoded_key(&self) -> String {
+        let key = self.key.replace('+', " ");
+        percent_decode_str(&key).decode_utf8_lossy().into_owned()
+    }
+}
+
+/// A batch of SQS messages, each carrying a JSON `Request` as its body.
+#[derive(Debug, Deserialize)]
+pub struct SqsEvent {
+    #[serde(rename = "Records")]
+    pub records: Vec<SqsMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SqsMessage {
+    pub message_id: String,
+    pub body: String,
+}
+
+/// The partial batch response understood by SQS event source mappings with
+/// `ReportBatchItemFailures` enabled; only the listed messages are redriven.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SqsBatchResponse {
+    pub batch_item_failures: Vec<SqsBatchItemFailure>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SqsBatchItemFailure {
+    pub item_identifier: String,
+}
+
+/// An HTTP request proxied by API Gateway or a Lambda function URL.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum HttpEvent {
+    /// Function URLs and HTTP APIs with payload format version 2.0.
+    V2(HttpEventV2),
+    /// REST APIs and HTTP APIs with payload format version 1.0.
+    V1(HttpEventV1),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpEventV2 {
+    pub raw_path: String,
+    pub request_context: HttpRequestContextV2,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Already URL-decoded, with repeated parameters joined by commas.
+    pub query_string_parameters: Option<HashMap<String, String>>,
+    pub body: Option<String>,
+    #[serde(default)]
+    pub is_base64_encoded: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HttpRequestContextV2 {
+    pub http: HttpDescriptionV2,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HttpDescriptionV2 {
+    pub method: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpEventV1 {
+    pub http_method: String,
+    pub path: String,
+    pub headers: Option<HashMap<String, String>>,
+    /// Already URL-decoded, keeping the last of repeated parameters.
+    pub query_string_parameters: Option<HashMap<String, String>>,
+    pub body: Option<String>,
+    #[serde(default)]
+    pub is_base64_encoded: bool,
+}
+
+/// The response shape both API Gateway and function URLs understand.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+    pub is_base64_encoded: bool,
+}
@@ -0,0 +1,374 @@
+//! Hashing every frame of an animation, so that content shown only partway
+//! through an animated WebP, GIF or APNG is caught too.
+//!
+//! Decoding an animated image as a still only yields its first frame. Here
+//! the frames are decoded one at a time, composited onto the canvas as a
+//! viewer would show them, and hashed as they go, so only one frame is held
+//! in memory at once.
+
+use crate::hash::{deserialize_pairs, serialize_hashes, DecodeLimits, HashSettings};
+use crate::index::IndexMatch;
+use crate::orientation::Orientation;
+use crate::timings::Timings;
+use crate::TypedError;
+use image::codecs::webp::WebPDecoder;
+use image::error::ImageError;
+use image::{AnimationDecoder, DynamicImage, Frames, GenericImageView, ImageDecoder, ImageFormat};
+use image_hasher::{HashAlg, Hasher, ImageHash};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::time::Instant;
+
+/// Which frames of an animation are hashed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameOptions {
+    /// Hash only every `every`th frame, starting with the first. Defaults
+    /// to every frame.
+    pub every: Option<u32>,
+    /// Skip frames whose primary hash is the same as that of the last frame
+    /// hashed, so still stretches of an animation are reported once.
+    #[serde(default)]
+    pub keyframes: bool,
+    /// Most frames hashed, within the function's own limit.
+    pub max_frames: Option<u32>,
+}
+
+impl FrameOptions {
+    /// Fails if `every` or `max_frames` is zero.
+    pub fn validate(&self) -> Result<(), TypedError> {
+        if self.every == Some(0) {
+            return Err(TypedError::InvalidRequest(
+                "`every` must be at least 1".to_string(),
+            ));
+        }
+        if self.max_frames == Some(0) {
+            return Err(TypedError::InvalidRequest(
+                "`max_frames` must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The hashes of one frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameHash {
+    /// Position of the frame in the animation, from 0.
+    pub index: u32,
+    /// When the frame is first shown, from the start of the animation.
+    pub timestamp_ms: u64,
+    /// Every requested hash of the frame, keyed by algorithm.
+    #[serde(
+        serialize_with = "serialize_hashes",
+        deserialize_with = "deserialize_pairs"
+    )]
+    pub hashes: Vec<(HashAlg, String)>,
+    /// Near-duplicates of the frame from the hash index, when
+    /// `max_distance` was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matches: Option<Vec<IndexMatch>>,
+    /// The frame hashed with the index's own settings, to look it up with.
+    #[serde(skip)]
+    pub lookup_hash: Option<ImageHash>,
+}
+
+/// The hashes of the sampled frames of an image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameHashes {
+    /// A single frame for images that aren't animated.
+    pub frames: Vec<FrameHash>,
+    /// Whether frames were left out because `max_frames` were hashed.
+    pub truncated: bool,
+    /// For each algorithm, the hash with every bit set that is set in most
+    /// of the frame hashes, which stays close to the hash of the dominant
+    /// content of the animation.
+    #[serde(
+        serialize_with = "serialize_hashes",
+        deserialize_with = "deserialize_pairs"
+    )]
+    pub aggregate: Vec<(HashAlg, String)>,
+}
+
+/// Hashes the frames of the encoded image `bytes` picked by `options`, at
+/// most `max_frames` of them, with each of `settings.algos`. Frames are
+/// hashed as is even when `settings.invariance` is set.
+///
+/// `first` is the image decoded as a still within `limits` and turned by
+/// `orientation`, which is hashed on its own if `bytes` isn't an animation.
+/// Each frame is also hashed with `lookup`, if given, into
+/// [`FrameHash::lookup_hash`]. Decoding and hashing times are added to
+/// `timings`.
+#[allow(clippy::too_many_arguments)]
+pub fn hash_frames(
+    bytes: &[u8],
+    first: &DynamicImage,
+    limits: &DecodeLimits,
+    orientation: Option<Orientation>,
+    settings: &HashSettings,
+    options: &FrameOptions,
+    max_frames: u32,
+    lookup: Option<&Hasher>,
+    timings: &mut Timings,
+) -> Result<FrameHashes, TypedError> {
+    let (width, height) = first.dimensions();
+    let decode_error = |e: ImageError| match e {
+        ImageError::Limits(_) => TypedError::ImageTooLarge(width, height),
+        e => TypedError::InvalidFormat(e.to_string()),
+    };
+    let hashers: Vec<(HashAlg, Hasher)> = settings
+        .algos
+        .iter()
+        .map(|&algo| (algo, settings.config.hasher_config(algo).to_hasher()))
+        .collect();
+    let hash_frame = |index: u32, timestamp_ms: u64, img: &DynamicImage| -> HashedFrame {
+        let hashes = hashers
+            .iter()
+            .map(|(algo, hasher)| (*algo, hasher.hash_image(img)))
+            .collect();
+        let lookup_hash = lookup.map(|hasher| hasher.hash_image(img));
+        (index, timestamp_ms, hashes, lookup_hash)
+    };
+
+    let start = Instant::now();
+    let animation = animation_frames(bytes, limits).map_err(decode_error)?;
+    Timings::add(&mut timings.decode, start);
+    let Some(mut animation) = animation else {
+        let start = Instant::now();
+        let frame = hash_frame(0, 0, first);
+        Timings::add(&mut timings.hash, start);
+        return Ok(collect(vec![frame], false));
+    };
+
+    let every = options.every.unwrap_or(1);
+    let max_frames = options
+        .max_frames
+        .map_or(max_frames, |max| max.min(max_frames));
+    let mut frames: Vec<HashedFrame> = Vec::new();
+    let mut truncated = false;
+    let mut elapsed_ms = 0.0;
+    for index in 0u32.. {
+        let start = Instant::now();
+        let Some(frame) = animation.next() else {
+            break;
+        };
+        let frame = frame.map_err(decode_error)?;
+        Timings::add(&mut timings.decode, start);
+        let timestamp_ms = elapsed_ms as u64;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        elapsed_ms += f64::from(numer) / f64::from(denom.max(1));
+        if index % every != 0 {
+            continue;
+        }
+        if frames.len() as u32 == max_frames {
+            truncated = true;
+            break;
+        }
+
+        let start = Instant::now();
+        let mut img = DynamicImage::ImageRgba8(frame.into_buffer());
+        if let Some(orientation) = orientation {
+            img = orientation.apply(img);
+        }
+        let hashed = hash_frame(index, timestamp_ms, &img);
+        Timings::add(&mut timings.hash, start);
+        let repeated = frames
+            .last()
+            .is_some_and(|last| last.2[0].1 == hashed.2[0].1);
+        if !(options.keyframes && repeated) {
+            frames.push(hashed);
+        }
+    }
+    // an animation whose frames all failed to show up still has the image
+    // decoded as a still.
+    if frames.is_empty() {
+        frames.push(hash_frame(0, 0, first));
+    }
+    tracing::info!(frames = frames.len(), truncated, "animation frames hashed");
+    Ok(collect(frames, truncated))
+}
+
+/// A frame's index, timestamp, hashes and lookup hash.
+type HashedFrame = (u32, u64, Vec<(HashAlg, ImageHash)>, Option<ImageHash>);
+
+fn collect(frames: Vec<HashedFrame>, truncated: bool) -> FrameHashes {
+    let algos: Vec<HashAlg> = frames[0].2.iter().map(|(algo, _)| *algo).collect();
+    let aggregate = algos
+        .iter()
+        .enumerate()
+        .map(|(i, &algo)| {
+            let hashes: Vec<&ImageHash> = frames.iter().map(|frame| &frame.2[i].1).collect();
+            (algo, majority(&hashes).to_base64())
+        })
+        .collect();
+    let frames = frames
+        .into_iter()
+        .map(|(index, timestamp_ms, hashes, lookup_hash)| FrameHash {
+            index,
+            timestamp_ms,
+            hashes: hashes
+                .into_iter()
+                .map(|(algo, hash)| (algo, hash.to_base64()))
+                .collect(),
+            matches: None,
+            lookup_hash,
+        })
+        .collect();
+    FrameHashes {
+        frames,
+        truncated,
+        aggregate,
+    }
+}
+
+/// The bitwise majority of `hashes`, which all have the same length.
+fn majority(hashes: &[&ImageHash]) -> ImageHash {
+    let bytes: Vec<u8> = (0..hashes[0].as_bytes().len())
+        .map(|i| {
+            (0..8).fold(0u8, |byte, bit| {
+                let ones = hashes
+                    .iter()
+                    .filter(|hash| hash.as_bytes()[i] >> bit & 1 == 1)
+                    .count();
+                if ones * 2 > hashes.len() {
+                    byte | 1 << bit
+                } else {
+                    byte
+                }
+            })
+        })
+        .collect();
+    ImageHash::from_bytes(&bytes).expect("hashes fit in their own length")
+}
+
+/// The frames of `bytes` if it is an animation in a format that can have
+/// them, or `None` for still images.
+fn animation_frames<'a>(
+    bytes: &'a [u8],
+    limits: &DecodeLimits,
+) -> Result<Option<Frames<'a>>, ImageError> {
+    let cursor = Cursor::new(bytes);
+    match image::guess_format(bytes) {
+        Ok(ImageFormat::WebP) => {
+            let mut decoder = WebPDecoder::new(cursor)?;
+            decoder.set_limits(limits.image_limits())?;
+            Ok(decoder.has_animation().then(|| decoder.into_frames()))
+        }
+        #[cfg(feature = "gif")]
+        Ok(ImageFormat::Gif) => {
+            let mut decoder = image::codecs::gif::GifDecoder::new(cursor)?;
+            decoder.set_limits(limits.image_limits())?;
+            Ok(Some(decoder.into_frames()))
+        }
+        #[cfg(feature = "png")]
+        Ok(ImageFormat::Png) => {
+            let mut decoder = image::codecs::png::PngDecoder::new(cursor)?;
+            decoder.set_limits(limits.image_limits())?;
+            match decoder.is_apng()? {
+                true => Ok(Some(decoder.apng()?.into_frames())),
+                false => Ok(None),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(bytes: &[u8], options: FrameOptions, max_frames: u32) -> FrameHashes {
+        let first = image::load_from_memory(bytes).unwrap();
+        let settings = HashSettings::default();
+        let limits = DecodeLimits::default();
+        let mut timings = Timings::default();
+        hash_frames(
+            bytes,
+            &first,
+            &limits,
+            None,
+            &settings,
+            &options,
+            max_frames,
+            None,
+            &mut timings,
+        )
+        .unwrap()
+    }
+
+    fn indexes(hashes: &FrameHashes) -> Vec<(u32, u64)> {
+        let frames = hashes.frames.iter();
+        frames
+            .map(|frame| (frame.index, frame.timestamp_ms))
+            .collect()
+    }
+
+    #[test]
+    fn still_images_are_a_single_frame() {
+        let hashes = hash(&crate::lambda::tests::jpeg(), FrameOptions::default(), 10);
+        assert_eq!(indexes(&hashes), [(0, 0)]);
+        assert!(!hashes.truncated);
+        assert_eq!(hashes.aggregate, hashes.frames[0].hashes);
+    }
+
+    #[cfg(feature = "gif")]
+    mod gif {
+        use super::*;
+        use image::codecs::gif::GifEncoder;
+        use image::{Delay, Frame, RgbaImage};
+
+        /// A 32x32 GIF showing each of `frames` for 100 ms, where `true` is
+        /// a gradient getting lighter across and `false` one getting darker,
+        /// which have opposite `Gradient` hashes.
+        fn gif(frames: &[bool]) -> Vec<u8> {
+            let mut bytes = Vec::new();
+            let mut encoder = GifEncoder::new(&mut bytes);
+            for &lighter in frames {
+                let img = RgbaImage::from_fn(32, 32, |x, _| {
+                    let value = if lighter { x * 8 } else { 255 - x * 8 } as u8;
+                    image::Rgba([value, value, value, 255])
+                });
+                let delay = Delay::from_numer_denom_ms(100, 1);
+                encoder
+                    .encode_frame(Frame::from_parts(img, 0, 0, delay))
+                    .unwrap();
+            }
+            drop(encoder);
+            bytes
+        }
+
+        #[test]
+        fn every_nth_frame_is_hashed_up_to_the_limit() {
+            let bytes = gif(&[true, false, true, false, true]);
+            let options = FrameOptions {
+                every: Some(2),
+                max_frames: Some(2),
+                ..FrameOptions::default()
+            };
+            let hashes = hash(&bytes, options, 100);
+            assert_eq!(indexes(&hashes), [(0, 0), (2, 200)]);
+            assert!(hashes.truncated);
+
+            // the function's own limit applies when the request has none.
+            let hashes = hash(&bytes, FrameOptions::default(), 3);
+            assert_eq!(indexes(&hashes), [(0, 0), (1, 100), (2, 200)]);
+            assert!(hashes.truncated);
+            let hashes = hash(&bytes, FrameOptions::default(), 5);
+            assert_eq!(hashes.frames.len(), 5);
+            assert!(!hashes.truncated);
+        }
+
+        #[test]
+        fn keyframes_skip_repeated_frames() {
+            let bytes = gif(&[true, true, false, false, true]);
+            let options = FrameOptions {
+                keyframes: true,
+                ..FrameOptions::default()
+            };
+            let hashes = hash(&bytes, options, 100);
+            assert_eq!(indexes(&hashes), [(0, 0), (2, 200), (4, 400)]);
+            assert_ne!(hashes.frames[0].hashes, hashes.frames[1].hashes);
+            // two of the three frames hashed are the same.
+            assert_eq!(hashes.aggregate, hashes.frames[0].hashes);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Decoding and hashing images, independent of where they are stored.
+
+use crate::invariant::{self, Invariance, Transform};
+use crate::metrics;
+use crate::options::HashConfig;
+use crate::orientation::read_orientation;
+use crate::timings::Timings;
+use crate::TypedError;
+use image::error::ImageError;
+use image::io::{Limits, Reader as ImageReader};
+use image::{DynamicImage, GenericImageView};
+use image_hasher::{HashAlg, Hasher};
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io::Cursor;
+use std::marker::PhantomData;
+use std::time::Instant;
+
+/// The algorithms and hasher settings to hash an image with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashSettings {
+    /// Must not be empty; the first one is reported as the primary hash.
+    pub algos: Vec<HashAlg>,
+    pub config: HashConfig,
+    /// Hash every rotation and mirroring of the image too.
+    pub invariance: Option<Invariance>,
+}
+
+impl Default for HashSettings {
+    /// A single `Gradient` hash with the default hasher settings.
+    fn default() -> Self {
+        HashSettings {
+            algos: vec![HashAlg::Gradient],
+            config: HashConfig::default(),
+            invariance: None,
+        }
+    }
+}
+
+/// Bounds on what is downloaded and decoded, so that a small file claiming
+/// huge dimensions can't exhaust the function's memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Largest object downloaded, in bytes, checked before downloading.
+    pub max_object_bytes: u64,
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Most memory the decoder may allocate, in bytes.
+    pub max_alloc: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_object_bytes: 50 * 1024 * 1024,
+            max_width: 16384,
+            max_height: 16384,
+            max_alloc: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// The decoder limits enforcing these bounds.
+    pub(crate) fn image_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+}
+
+/// The base64 hash of each transform of an image.
+pub type TransformHashes = Vec<(Transform, String)>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HashResult {
+    pub hash_base64: String,
+    pub algo: HashAlg,
+    /// Every requested hash keyed by algorithm; `hash_base64` and `algo`
+    /// repeat the first of them.
+    #[serde(
+        serialize_with = "serialize_hashes",
+        deserialize_with = "deserialize_pairs"
+    )]
+    pub hashes: Vec<(HashAlg, String)>,
+    /// The hasher settings the hashes were computed with.
+    pub config: HashConfig,
+    /// How the hashes are invariant to rotating and mirroring, if at all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invariance: Option<Invariance>,
+    /// With [`Invariance::All`], the hash of every transform of the image,
+    /// keyed by algorithm and then by transform.
+    #[serde(
+        serialize_with = "serialize_transforms",
+        deserialize_with = "deserialize_transforms",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub transforms: Option<Vec<(HashAlg, TransformHashes)>>,
+    pub image_size: (u32, u32),
+    pub time_elapsed: f64,
+}
+
+pub(crate) fn serialize_hashes<S: Serializer>(
+    hashes: &[(HashAlg, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(hashes.iter().map(|(algo, hash)| (algo, hash)))
+}
+
+fn serialize_transforms<S: Serializer>(
+    transforms: &Option<Vec<(HashAlg, TransformHashes)>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    struct ByTransform<'a>(&'a [(Transform, String)]);
+    impl Serialize for ByTransform<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().map(|(transform, hash)| (transform, hash)))
+        }
+    }
+    let transforms = transforms.as_deref().unwrap_or_default();
+    serializer.collect_map(
+        transforms
+            .iter()
+            .map(|(algo, hashes)| (algo, ByTransform(hashes))),
+    )
+}
+
+/// Reads a map into its entries, in order.
+pub(crate) fn deserialize_pairs<'de, D, K, V>(deserializer: D) -> Result<Vec<(K, V)>, D::Error>
+where
+    D: Deserializer<'de>,
+    K: Deserialize<'de>,
+    V: Deserialize<'de>,
+{
+    struct PairsVisitor<K, V>(PhantomData<(K, V)>);
+    impl<'de, K: Deserialize<'de>, V: Deserialize<'de>> Visitor<'de> for PairsVisitor<K, V> {
+        type Value = Vec<(K, V)>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut pairs = Vec::with_capacity(map.size_hint().unwrap_or_default());
+            while let Some(pair) = map.next_entry()? {
+                pairs.push(pair);
+            }
+            Ok(pairs)
+        }
+    }
+    deserializer.deserialize_map(PairsVisitor(PhantomData))
+}
+
+fn deserialize_transforms<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<(HashAlg, TransformHashes)>>, D::Error> {
+    struct ByTransform(TransformHashes);
+    impl<'de> Deserialize<'de> for ByTransform {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserialize_pairs(deserializer).map(ByTransform)
+        }
+    }
+    let transforms: Vec<(HashAlg, ByTransform)> = deserialize_pairs(deserializer)?;
+    Ok(Some(
+        transforms
+            .into_iter()
+            .map(|(algo, hashes)| (algo, hashes.0))
+            .collect(),
+    ))
+}
+
+/// Decodes an encoded image, guessing its format from its contents.
+///
+/// The dimensions are read from the header first, so images over `limits`
+/// are refused before any pixel memory is allocated.
+pub fn decode_image(bytes: &[u8], limits: &DecodeLimits) -> Result<DynamicImage, TypedError> {
+    decode_image_timed(bytes, limits, &mut Timings::default())
+}
+
+/// [`decode_image`], adding the time spent reading the header and decoding
+/// to `timings`. Failures are counted in the `decode_failures` metric.
+pub fn decode_image_timed(
+    bytes: &[u8],
+    limits: &DecodeLimits,
+    timings: &mut Timings,
+) -> Result<DynamicImage, TypedError> {
+    let result = decode_within(bytes, limits, timings);
+    if result.is_err() {
+        metrics::decode_failed(image::guess_format(bytes).ok());
+    }
+    result
+}
+
+fn decode_within(
+    bytes: &[u8],
+    limits: &DecodeLimits,
+    timings: &mut Timings,
+) -> Result<DynamicImage, TypedError> {
+    let start = Instant::now();
+    let reader = || {
+        ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .map_err(|e| TypedError::InvalidFormat(e.to_string()))
+    };
+    let (width, height) = reader()?
+        .into_dimensions()
+        .map_err(|e| TypedError::InvalidFormat(e.to_string()))?;
+    Timings::add(&mut timings.format_detection, start);
+    if width > limits.max_width || height > limits.max_height {
+        return Err(TypedError::ImageTooLarge(width, height));
+    }
+
+    let start = Instant::now();
+    let mut reader = reader()?;
+    reader.limits(limits.image_limits());
+    let img = reader.decode().map_err(|e| match e {
+        ImageError::Limits(_) => TypedError::ImageTooLarge(width, height),
+        e => TypedError::InvalidFormat(e.to_string()),
+    })?;
+    Timings::add(&mut timings.decode, start);
+    Ok(img)
+}
+
+/// Hashes a decoded image with each of `settings.algos`, and in each of its
+/// transforms if `settings.invariance` says so.
+///
+/// # Panics
+///
+/// If `settings.algos` is empty.
+pub fn hash_image(img: &DynamicImage, settings: &HashSettings) -> HashResult {
+    // get image size
+    let (width, height) = img.dimensions();
+
+    // get hashing timing, over all algorithms and transforms of the decoded image
+    let start = std::time::Instant::now();
+    let hashers: Vec<Hasher> = settings
+        .algos
+        .iter()
+        .map(|&algo| settings.config.hasher_config(algo).to_hasher())
+        .collect();
+    let by_algo = match settings.invariance {
+        Some(_) => invariant::hash_transforms(img, &hashers),
+        None => hashers
+            .iter()
+            .map(|hasher| vec![(Transform::Identity, hasher.hash_image(img))])
+            .collect(),
+    };
+    let mut hashes = Vec::with_capacity(settings.algos.len());
+    let mut transforms = Vec::new();
+    for (&algo, all) in settings.algos.iter().zip(by_algo) {
+        let hash = match settings.invariance {
+            Some(Invariance::Canonical) => invariant::canonical(&all),
+            _ => &all[0].1,
+        };
+        hashes.push((algo, hash.to_base64()));
+        if settings.invariance == Some(Invariance::All) {
+            let all = all.iter().map(|(t, hash)| (*t, hash.to_base64()));
+            transforms.push((algo, all.collect()));
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let (algo, hash_base64) = hashes[0].clone();
+    HashResult {
+        hash_base64,
+        algo,
+        hashes,
+        config: settings.config.clone(),
+        invariance: settings.invariance,
+        transforms: (settings.invariance == Some(Invariance::All)).then_some(transforms),
+        image_size: (width, height),
+        time_elapsed: elapsed.as_secs_f64(),
+    }
+}
+
+/// Decodes and hashes an encoded image, exactly as the Lambda function does
+/// for objects it downloads, within the default [`DecodeLimits`] and turned
+/// upright as its EXIF orientation says.
+pub fn hash_bytes(bytes: &[u8], settings: &HashSettings) -> Result<HashResult, TypedError> {
+    if settings.algos.is_empty() {
+        return Err(TypedError::InvalidRequest(
+            "at least one algorithm must be given".to_string(),
+        ));
+    }
+    let mut img = decode_image(bytes, &DecodeLimits::default())?;
+    if let Some(orientation) = read_orientation(bytes) {
+        img = orientation.apply(img);
+    }
+    Ok(hash_image(&img, settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let img = RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut bytes, ImageFormat::Jpeg)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn dimensions_are_checked_before_decoding() {
+        let mut bytes = jpeg(16, 16);
+        // the baseline start of frame segment holds the height and then the
+        // width, after its length and sample precision.
+        let sof = bytes.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        bytes[sof + 5..sof + 9].copy_from_slice(&[0xEA, 0x60, 0xEA, 0x60]);
+        let err = decode_image(&bytes, &DecodeLimits::default()).unwrap_err();
+        assert_eq!(err.code(), "image_too_large");
+        assert_eq!(
+            err.to_string(),
+            "Image of 60000x60000 pixels is over the decode limits"
+        );
+    }
+
+    #[test]
+    fn allocations_are_limited() {
+        let bytes = jpeg(64, 48);
+        assert!(decode_image(&bytes, &DecodeLimits::default()).is_ok());
+        let limits = DecodeLimits {
+            max_alloc: 1024,
+            ..DecodeLimits::default()
+        };
+        let err = decode_image(&bytes, &limits).unwrap_err();
+        assert_eq!(err.code(), "image_too_large");
+        assert_eq!(
+            err.to_string(),
+            "Image of 64x48 pixels is over the decode limits"
+        );
+    }
+}
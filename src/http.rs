@@ -0,0 +1,390 @@
+//! Serving the function over HTTP, behind API Gateway or a function URL.
+//!
+//! Requests to any path ending in `/hash` are handled, so the function works
+//! the same under API Gateway stages and custom base paths:
+//!
+//! - `GET /hash?path=..&algo=..` hashes an S3 object, taking the fields of a
+//!   [`Request`] as query parameters. `register` and `write_back` are
+//!   refused, as a GET must not change anything.
+//! - `POST /hash` hashes the image in the request body, sent either as is or
+//!   as the `image` part of a `multipart/form-data` form, with the other
+//!   fields of a [`Request`] as query parameters or form fields.
+
+use crate::events::{HttpEvent, HttpResponse};
+use crate::lambda::{put_object, Request, Response, State};
+use crate::writeback::WriteBackMode;
+use crate::{ErrorObject, TypedError};
+use base64::Engine;
+use bytes::Bytes;
+use futures::stream;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::time::Instant;
+
+/// The parts of an HTTP request the routes look at, whichever payload format
+/// it came in.
+struct HttpRequest {
+    method: String,
+    path: String,
+    /// Keyed by lowercase header name.
+    headers: HashMap<String, String>,
+    query: HashMap<String, String>,
+    body: Option<String>,
+    is_base64_encoded: bool,
+}
+
+impl From<HttpEvent> for HttpRequest {
+    fn from(event: HttpEvent) -> Self {
+        let (method, path, headers, query, body, is_base64_encoded) = match event {
+            HttpEvent::V2(event) => (
+                event.request_context.http.method,
+                event.raw_path,
+                event.headers,
+                event.query_string_parameters,
+                event.body,
+                event.is_base64_encoded,
+            ),
+            HttpEvent::V1(event) => (
+                event.http_method,
+                event.path,
+                event.headers.unwrap_or_default(),
+                event.query_string_parameters,
+                event.body,
+                event.is_base64_encoded,
+            ),
+        };
+        HttpRequest {
+            method: method.to_ascii_uppercase(),
+            path,
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value))
+                .collect(),
+            query: query.unwrap_or_default(),
+            body,
+            is_base64_encoded,
+        }
+    }
+}
+
+impl HttpRequest {
+    fn body_bytes(&mut self) -> Result<Bytes, TypedError> {
+        let body = self.body.take().unwrap_or_default();
+        if self.is_base64_encoded {
+            base64::engine::general_purpose::STANDARD
+                .decode(body)
+                .map(Bytes::from)
+                .map_err(|e| TypedError::InvalidRequest(format!("invalid base64 body: {e}")))
+        } else {
+            Ok(Bytes::from(body))
+        }
+    }
+}
+
+pub async fn handle_http(
+    state: &State,
+    event: HttpEvent,
+    deadline: Option<Instant>,
+) -> HttpResponse {
+    let mut request = HttpRequest::from(event);
+    tracing::info!(method = %request.method, path = %request.path, "handling an HTTP request");
+
+    if request.path.trim_end_matches('/').rsplit('/').next() != Some("hash") {
+        return error_response(
+            404,
+            ErrorObject {
+                code: "no_route",
+                message: format!("no route for `{}`", request.path),
+                retryable: false,
+                key: None,
+            },
+        );
+    }
+    let result = match request.method.as_str() {
+        "GET" => hash_object(state, request.query, deadline).await,
+        "POST" => hash_upload(state, &mut request, deadline).await,
+        _ => {
+            let mut response = error_response(
+                405,
+                ErrorObject {
+                    code: "method_not_allowed",
+                    message: format!("method {} is not allowed", request.method),
+                    retryable: false,
+                    key: None,
+                },
+            );
+            response
+                .headers
+                .insert("allow".to_string(), "GET, POST".to_string());
+            return response;
+        }
+    };
+    match result {
+        Ok(response) => json_response(200, &response),
+        Err(err) => {
+            tracing::error!(err = %err, "failed to handle HTTP request");
+            error_response(status_code(&err), ErrorObject::from(&err))
+        }
+    }
+}
+
+async fn hash_object(
+    state: &State,
+    query: HashMap<String, String>,
+    deadline: Option<Instant>,
+) -> Result<Response, TypedError> {
+    // crawlers and caches may fetch or repeat a GET at will.
+    if let Some(name) = ["register", "write_back"]
+        .into_iter()
+        .find(|name| query.contains_key(*name))
+    {
+        return Err(TypedError::InvalidRequest(format!(
+            "`{name}` changes state, so it isn't allowed on GET"
+        )));
+    }
+    let mut request: Request = serde_json::from_value(fields_to_json(query))
+        .map_err(|e| TypedError::InvalidRequest(e.to_string()))?;
+    // and the `WRITE_BACK` default doesn't apply to them either.
+    request.write_back = Some(WriteBackMode::None);
+    request.register = false;
+    put_object(state, request, deadline).await
+}
+
+/// Hashes the uploaded image as a [`Request`] carrying it as `data`, with
+/// the other fields of the request as its options.
+async fn hash_upload(
+    state: &State,
+    request: &mut HttpRequest,
+    deadline: Option<Instant>,
+) -> Result<Response, TypedError> {
+    let body = request.body_bytes()?;
+    let content_type = request
+        .headers
+        .get("content-type")
+        .cloned()
+        .unwrap_or_default();
+    let mut fields = std::mem::take(&mut request.query);
+    let image = if content_type
+        .to_ascii_lowercase()
+        .starts_with("multipart/form-data")
+    {
+        read_multipart(&content_type, body, &mut fields).await?
+    } else {
+        body
+    };
+    if image.is_empty() {
+        return Err(TypedError::InvalidRequest(
+            "no image in the request body".to_string(),
+        ));
+    }
+    let request = Request {
+        data: Some(image),
+        ..serde_json::from_value(fields_to_json(fields))
+            .map_err(|e| TypedError::InvalidRequest(e.to_string()))?
+    };
+    put_object(state, request, deadline).await
+}
+
+/// Reads the uploaded image out of a `multipart/form-data` body, collecting
+/// the other fields into `fields`. The image is the part named `image`, or
+/// else the one part with a file name.
+async fn read_multipart(
+    content_type: &str,
+    body: Bytes,
+    fields: &mut HashMap<String, String>,
+) -> Result<Bytes, TypedError> {
+    let invalid = |e: multer::Error| TypedError::InvalidRequest(format!("invalid form: {e}"));
+    let boundary = multer::parse_boundary(content_type).map_err(invalid)?;
+    let body = stream::once(async move { Ok::<_, Infallible>(body) });
+    let mut multipart = multer::Multipart::new(body, boundary);
+
+    let mut image = None;
+    while let Some(field) = multipart.next_field().await.map_err(invalid)? {
+        if field.name() == Some("image") || field.file_name().is_some() {
+            if image.is_some() {
+                return Err(TypedError::InvalidRequest(
+                    "only one image can be uploaded at a time".to_string(),
+                ));
+            }
+            image = Some(field.bytes().await.map_err(invalid)?);
+        } else if let Some(name) = field.name().map(str::to_string) {
+            fields.insert(name, field.text().await.map_err(invalid)?);
+        }
+    }
+    image.ok_or_else(|| TypedError::InvalidRequest("no `image` part in the form".to_string()))
+}
+
+/// Turns query parameters or form fields into the JSON a [`Request`] is read
+/// from. Values that parse as JSON keep their type, so `hash_width=16` is a
+/// number and `preproc_diff_gauss=[1.5,3]` an array, while `algos` is a comma
+/// separated list. Locations and inline data are always strings, whatever
+/// they look like.
+fn fields_to_json(fields: HashMap<String, String>) -> Value {
+    let object = fields
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match name.as_str() {
+                "path" | "bucket" | "url" | "data_base64" => Value::String(value),
+                "algos" => value
+                    .split(',')
+                    .map(|algo| Value::String(algo.trim().to_string()))
+                    .collect(),
+                _ => serde_json::from_str(&value).unwrap_or(Value::String(value)),
+            };
+            (name, value)
+        })
+        .collect();
+    Value::Object(object)
+}
+
+/// The HTTP status reporting `err`.
+fn status_code(err: &TypedError) -> u16 {
+    match err {
+        TypedError::InvalidPath(_)
+        | TypedError::MissingBucket(_)
+        | TypedError::InvalidRequest(_)
+        | TypedError::InvalidUrl(_) => 400,
+        TypedError::UrlNotAllowed(_) | TypedError::S3AccessDenied(_, _) => 403,
+        TypedError::NotFound(_, _) => 404,
+        TypedError::UrlTooLarge(_, _)
+        | TypedError::ImageTooLarge(_, _)
+        | TypedError::ObjectTooLarge(_, _, _) => 413,
+        TypedError::InvalidFormat(_) | TypedError::NotAnImage(_, _) => 415,
+        TypedError::S3Get(_, _)
+        | TypedError::S3Download(_, _)
+        | TypedError::S3Put(_, _)
+        | TypedError::S3Unavailable(_, _)
+        | TypedError::UrlFetch(_, _)
+        | TypedError::UrlStatus(_, _)
+        | TypedError::TooManyRedirects(_) => 502,
+        TypedError::S3Throttled(_, _) => 503,
+        TypedError::UrlTimeout(_) | TypedError::S3Timeout(_, _) => 504,
+        TypedError::InvalidIndex(_) | TypedError::Read(_, _) => 500,
+    }
+}
+
+fn json_response(status_code: u16, body: &impl Serialize) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: HashMap::from([("content-type".to_string(), "application/json".to_string())]),
+        body: serde_json::to_string(body).expect("responses always serialize"),
+        is_base64_encoded: false,
+    }
+}
+
+fn error_response(status_code: u16, error: ErrorObject) -> HttpResponse {
+    json_response(status_code, &serde_json::json!({ "error": error }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lambda::tests::{jpeg, state};
+    use crate::lambda::Config;
+    use crate::s3::S3Location;
+    use crate::source::MemorySource;
+    use serde_json::json;
+
+    fn get(query: Value) -> HttpEvent {
+        serde_json::from_value(json!({
+            "httpMethod": "GET",
+            "path": "/hash",
+            "queryStringParameters": query,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn gets_never_write_back() {
+        let mut source = MemorySource::new();
+        let location = S3Location {
+            bucket: "photos".to_string(),
+            key: "cat.jpg".to_string(),
+        };
+        source.insert(location, jpeg());
+        let config = Config {
+            write_back: WriteBackMode::Tags,
+            ..Config::default()
+        };
+        let state = state(source, config);
+
+        let event = get(json!({"path": "s3://photos/cat.jpg"}));
+        let response = handle_http(&state, event, None).await;
+        assert_eq!(response.status_code, 200, "{}", response.body);
+        let body: Value = serde_json::from_str(&response.body).unwrap();
+        assert!(body["hash_base64"].is_string(), "{body}");
+        assert_eq!(body.get("write_back"), None, "{body}");
+
+        let event = get(json!({"path": "s3://photos/cat.jpg", "write_back": "tags"}));
+        let response = handle_http(&state, event, None).await;
+        assert_eq!(response.status_code, 400, "{}", response.body);
+    }
+
+    fn post(query: Value, image: &[u8]) -> HttpEvent {
+        serde_json::from_value(json!({
+            "httpMethod": "POST",
+            "path": "/hash",
+            "queryStringParameters": query,
+            "body": base64::engine::general_purpose::STANDARD.encode(image),
+            "isBase64Encoded": true,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_are_hashed_like_requests() {
+        let image = jpeg();
+        let config = Config {
+            max_inline_bytes: image.len() - 1,
+            ..Config::default()
+        };
+        let small = state(MemorySource::new(), config);
+        let response = handle_http(&small, post(json!({}), &image), None).await;
+        assert_eq!(response.status_code, 400, "{}", response.body);
+
+        let state = state(MemorySource::new(), Config::default());
+
+        let event = post(json!({"algos": "Mean,Gradient", "frames": "{}"}), &image);
+        let response = handle_http(&state, event, None).await;
+        assert_eq!(response.status_code, 200, "{}", response.body);
+        let uploaded: Value = serde_json::from_str(&response.body).unwrap();
+        let inline = Request {
+            data_base64: Some(base64::engine::general_purpose::STANDARD.encode(&image)),
+            ..serde_json::from_value(json!({"algos": ["Mean", "Gradient"], "frames": {}})).unwrap()
+        };
+        let inline = serde_json::to_value(put_object(&state, inline, None).await.unwrap()).unwrap();
+        assert_eq!(uploaded["hashes"], inline["hashes"]);
+        assert_eq!(uploaded["digests"], inline["digests"]);
+        assert_eq!(uploaded["frames"]["aggregate"], uploaded["hashes"]);
+
+        let event = post(json!({"register": "true"}), &image);
+        let response = handle_http(&state, event, None).await;
+        assert_eq!(response.status_code, 400, "{}", response.body);
+        let event = post(json!({"hash_width": "0"}), &image);
+        let response = handle_http(&state, event, None).await;
+        assert_eq!(response.status_code, 400, "{}", response.body);
+    }
+
+    #[test]
+    fn locations_and_data_stay_strings() {
+        let fields = HashMap::from([
+            ("path".to_string(), "123".to_string()),
+            ("url".to_string(), "null".to_string()),
+            ("data_base64".to_string(), "1234".to_string()),
+            ("hash_width".to_string(), "16".to_string()),
+            ("algos".to_string(), "Mean, Gradient".to_string()),
+        ]);
+        assert_eq!(
+            fields_to_json(fields),
+            json!({
+                "path": "123",
+                "url": "null",
+                "data_base64": "1234",
+                "hash_width": 16,
+                "algos": ["Mean", "Gradient"],
+            })
+        );
+    }
+}
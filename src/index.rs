@@ -0,0 +1,485 @@
+//! A near-duplicate index of image hashes, persisted as a single S3 object.
+//!
+//! Hashes are kept in a [BK-tree](https://en.wikipedia.org/wiki/BK-tree) over
+//! the Hamming distance, so looking up everything within a few bits of a hash
+//! only visits a small part of the tree.
+
+use crate::invariant::{self, Transform};
+use crate::options::HashConfig;
+use crate::s3::{s3_error, S3Location};
+use crate::TypedError;
+use aws_sdk_s3::primitives::ByteStream;
+use image::DynamicImage;
+use image_hasher::{HashAlg, Hasher, ImageHash};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+/// An entry of the index within the queried distance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexMatch {
+    /// The `s3://bucket/key` URI or the URL the hash was registered under.
+    pub key: String,
+    pub hash_base64: String,
+    pub distance: u32,
+    /// For invariant lookups, the transform of the queried image closest to
+    /// the registered one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Transform>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Node {
+    hash_base64: String,
+    /// Every key with exactly this hash; empty once they were all moved away.
+    keys: Vec<String>,
+    /// `(distance, node index)` pairs, one per distinct distance.
+    children: Vec<(u32, usize)>,
+}
+
+/// The serialized form of the index. All hashes in it are computed with the
+/// same algorithm and hasher settings, otherwise distances are meaningless.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BkTree {
+    algo: HashAlg,
+    config: HashConfig,
+    nodes: Vec<Node>,
+}
+
+impl BkTree {
+    pub fn new(algo: HashAlg, config: HashConfig) -> Self {
+        BkTree {
+            algo,
+            config,
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Fails unless every node holds a hash of the tree's own settings and
+    /// only points to nodes after it, so that reading a corrupt index is an
+    /// error instead of a panic or an endless loop on a later lookup.
+    fn check(&self) -> Result<(), TypedError> {
+        let bytes = self.config.hash_bits(self.algo).div_ceil(8) as usize;
+        for (index, node) in self.nodes.iter().enumerate() {
+            let invalid =
+                |problem: &str| Err(TypedError::InvalidIndex(format!("node {index} {problem}")));
+            match ImageHash::<Box<[u8]>>::from_base64(&node.hash_base64) {
+                Ok(hash) if hash.as_bytes().len() == bytes => {}
+                Ok(_) => {
+                    return invalid(&format!("holds a hash of another size than {bytes} bytes"))
+                }
+                Err(_) => return invalid("holds a hash that isn't base64"),
+            }
+            if node
+                .children
+                .iter()
+                .any(|&(_, child)| child <= index || child >= self.nodes.len())
+            {
+                return invalid("points to a node that isn't after it");
+            }
+        }
+        Ok(())
+    }
+
+    /// Every registered key whose hash is at most `max_distance` bits away,
+    /// closest first.
+    pub fn find(&self, hash: &ImageHash, max_distance: u32) -> Vec<IndexMatch> {
+        let mut matches = Vec::new();
+        if self.nodes.is_empty() {
+            return matches;
+        }
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            let node = &self.nodes[index];
+            let distance = decode(&node.hash_base64).dist(hash);
+            if distance <= max_distance {
+                matches.extend(node.keys.iter().map(|key| IndexMatch {
+                    key: key.clone(),
+                    hash_base64: node.hash_base64.clone(),
+                    distance,
+                    transform: None,
+                }));
+            }
+            // by the triangle inequality, only children whose edge is within
+            // `max_distance` of `distance` can hold matches.
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(edge, _)| edge.abs_diff(distance) <= max_distance)
+                    .map(|&(_, child)| child),
+            );
+        }
+        sort_matches(&mut matches);
+        matches
+    }
+
+    /// Every registered key within `max_distance` bits of the closest of
+    /// `hashes`, the transforms of one image, each key reported once.
+    pub fn find_closest(
+        &self,
+        hashes: &[(Transform, ImageHash)],
+        max_distance: u32,
+    ) -> Vec<IndexMatch> {
+        let mut closest: Vec<IndexMatch> = Vec::new();
+        for (transform, hash) in hashes {
+            for found in self.find(hash, max_distance) {
+                match closest.iter_mut().find(|m| m.key == found.key) {
+                    Some(known) if known.distance <= found.distance => {}
+                    Some(known) => {
+                        *known = IndexMatch {
+                            transform: Some(*transform),
+                            ..found
+                        }
+                    }
+                    None => closest.push(IndexMatch {
+                        transform: Some(*transform),
+                        ..found
+                    }),
+                }
+            }
+        }
+        sort_matches(&mut closest);
+        closest
+    }
+
+    /// Registers `key` under `hash`, dropping whatever hash it had before.
+    /// Returns `false` if it was already registered with this exact hash.
+    ///
+    /// Finding the earlier registration visits every node; use
+    /// [`BkTree::insert_new`] for keys known not to be registered.
+    pub fn insert(&mut self, key: &str, hash: &ImageHash) -> bool {
+        let hash_base64 = hash.to_base64();
+        for node in &mut self.nodes {
+            if let Some(position) = node.keys.iter().position(|k| k == key) {
+                if node.hash_base64 == hash_base64 {
+                    return false;
+                }
+                node.keys.remove(position);
+            }
+        }
+        self.insert_new(key, hash);
+        true
+    }
+
+    /// Registers `key`, which must not be registered yet, under `hash`. Only
+    /// the path down to the new node is visited, so adding a key takes time
+    /// in the depth of the tree rather than in its size.
+    pub fn insert_new(&mut self, key: &str, hash: &ImageHash) {
+        let node = Node {
+            hash_base64: hash.to_base64(),
+            keys: vec![key.to_string()],
+            children: Vec::new(),
+        };
+        if self.nodes.is_empty() {
+            self.nodes.push(node);
+            return;
+        }
+
+        let mut index = 0;
+        loop {
+            let distance = decode(&self.nodes[index].hash_base64).dist(hash);
+            if distance == 0 {
+                self.nodes[index].keys.push(key.to_string());
+                return;
+            }
+            let child = self.nodes[index]
+                .children
+                .iter()
+                .find(|(edge, _)| *edge == distance)
+                .map(|&(_, child)| child);
+            match child {
+                Some(child) => index = child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(node);
+                    self.nodes[index].children.push((distance, child));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Closest first, then by key.
+fn sort_matches(matches: &mut [IndexMatch]) {
+    matches.sort_by(|a, b| a.distance.cmp(&b.distance).then_with(|| a.key.cmp(&b.key)));
+}
+
+fn decode(hash_base64: &str) -> ImageHash {
+    // hashes read from S3 are checked when read, and the rest come from
+    // `ImageHash::to_base64`.
+    ImageHash::from_base64(hash_base64).expect("index holds a corrupt hash")
+}
+
+/// The index loaded once per cold start, and the object it is saved to.
+pub struct HashIndex {
+    location: S3Location,
+    saved: Mutex<Saved>,
+}
+
+/// The index as last read from or written to S3.
+struct Saved {
+    tree: BkTree,
+    /// The ETag of the object holding `tree`, if it exists yet.
+    e_tag: Option<String>,
+}
+
+/// The index object in S3, compared to the version already known.
+enum Fetched {
+    Missing,
+    NotModified,
+    Found(Saved),
+}
+
+impl HashIndex {
+    /// Loads the index from S3, starting an empty one with `algo` and `config`
+    /// if the object doesn't exist yet.
+    pub async fn load(
+        s3_client: &aws_sdk_s3::Client,
+        location: S3Location,
+        algo: HashAlg,
+        config: HashConfig,
+    ) -> Result<Self, TypedError> {
+        let saved = match fetch(s3_client, &location, None).await? {
+            Fetched::Found(saved) => {
+                tracing::info!(
+                    bucket = %location.bucket,
+                    key = %location.key,
+                    nodes = saved.tree.nodes.len(),
+                    "hash index loaded from S3",
+                );
+                saved
+            }
+            Fetched::Missing | Fetched::NotModified => {
+                tracing::info!(
+                    bucket = %location.bucket,
+                    key = %location.key,
+                    "no hash index in S3 yet, starting an empty one",
+                );
+                Saved {
+                    tree: BkTree::new(algo, config),
+                    e_tag: None,
+                }
+            }
+        };
+        Ok(HashIndex {
+            location,
+            saved: Mutex::new(saved),
+        })
+    }
+
+    /// The algorithm and hasher settings every hash in the index uses.
+    pub async fn hasher_settings(&self) -> (HashAlg, HashConfig) {
+        let saved = self.saved.lock().await;
+        (saved.tree.algo, saved.tree.config.clone())
+    }
+
+    /// A hasher with the index's own settings, which may differ from those
+    /// of the request that brought an image.
+    pub async fn hasher(&self) -> Hasher {
+        let (algo, config) = self.hasher_settings().await;
+        config.hasher_config(algo).to_hasher()
+    }
+
+    /// Hashes `img` with the index's own settings.
+    pub async fn hash_image(&self, img: &DynamicImage) -> ImageHash {
+        self.hasher().await.hash_image(img)
+    }
+
+    /// Every registered key whose hash is at most `max_distance` bits away.
+    pub async fn find(&self, hash: &ImageHash, max_distance: u32) -> Vec<IndexMatch> {
+        self.saved.lock().await.tree.find(hash, max_distance)
+    }
+
+    /// Every registered key within `max_distance` bits of the closest
+    /// rotation or mirroring of `img`, hashed with the index's own settings.
+    pub async fn find_invariant(&self, img: &DynamicImage, max_distance: u32) -> Vec<IndexMatch> {
+        let hashes = invariant::transform_hashes(img, &self.hasher().await);
+        self.saved
+            .lock()
+            .await
+            .tree
+            .find_closest(&hashes, max_distance)
+    }
+
+    /// Registers `key` under `hash` and saves the index back to S3.
+    ///
+    /// The index is read again first, if it changed since this instance last
+    /// saw it, so that registrations saved by other instances are kept. The
+    /// new registration only shows up in lookups once it is saved, so a
+    /// failed save can be retried.
+    ///
+    /// The lock is held from reading to saving so that concurrent inserts
+    /// within the same instance are applied one after the other. Instances
+    /// saving at the very same time can still lose one of their additions.
+    pub async fn insert(
+        &self,
+        s3_client: &aws_sdk_s3::Client,
+        key: &str,
+        hash: &ImageHash,
+    ) -> Result<(), TypedError> {
+        let mut saved = self.saved.lock().await;
+        if let Fetched::Found(latest) =
+            fetch(s3_client, &self.location, saved.e_tag.as_deref()).await?
+        {
+            tracing::info!(
+                nodes = latest.tree.nodes.len(),
+                "hash index changed in S3, reloaded before saving"
+            );
+            *saved = latest;
+        }
+        let mut tree = saved.tree.clone();
+        if !tree.insert(key, hash) {
+            return Ok(());
+        }
+        let body =
+            serde_json::to_vec(&tree).map_err(|e| TypedError::InvalidIndex(e.to_string()))?;
+        let output = s3_client
+            .put_object()
+            .bucket(&self.location.bucket)
+            .key(&self.location.key)
+            .content_type("application/json")
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .map_err(|err| {
+                tracing::error!(
+                    err = %err,
+                    bucket = %self.location.bucket,
+                    key = %self.location.key,
+                    "failed to save the hash index to S3"
+                );
+                s3_error(&self.location, err, TypedError::S3Put)
+            })?;
+        tracing::info!(key = %key, nodes = tree.nodes.len(), "hash index saved to S3");
+        *saved = Saved {
+            tree,
+            e_tag: output.e_tag,
+        };
+        Ok(())
+    }
+}
+
+/// Reads the index at `location`, unless its ETag is still `e_tag`.
+async fn fetch(
+    s3_client: &aws_sdk_s3::Client,
+    location: &S3Location,
+    e_tag: Option<&str>,
+) -> Result<Fetched, TypedError> {
+    let response = s3_client
+        .get_object()
+        .bucket(&location.bucket)
+        .key(&location.key)
+        .set_if_none_match(e_tag.map(str::to_string))
+        .send()
+        .await;
+    match response {
+        Ok(output) => {
+            let data = output
+                .body
+                .collect()
+                .await
+                .map_err(|e| TypedError::S3Download(location.to_string(), Box::new(e)))?;
+            let tree: BkTree = serde_json::from_slice(&data.into_bytes())
+                .map_err(|e| TypedError::InvalidIndex(e.to_string()))?;
+            tree.check()?;
+            Ok(Fetched::Found(Saved {
+                tree,
+                e_tag: output.e_tag,
+            }))
+        }
+        Err(err) if err.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+            Ok(Fetched::Missing)
+        }
+        Err(err)
+            if err
+                .raw_response()
+                .is_some_and(|r| r.status().as_u16() == 304) =>
+        {
+            Ok(Fetched::NotModified)
+        }
+        Err(err) => {
+            tracing::error!(
+                err = %err,
+                bucket = %location.bucket,
+                key = %location.key,
+                "failed to retrieve the hash index from S3"
+            );
+            Err(s3_error(location, err, TypedError::S3Get))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(first: u8) -> ImageHash {
+        ImageHash::from_bytes(&[first, 0, 0, 0, 0, 0, 0, 0]).unwrap()
+    }
+
+    fn keys(matches: Vec<IndexMatch>) -> Vec<String> {
+        matches.into_iter().map(|m| m.key).collect()
+    }
+
+    #[test]
+    fn insert_replaces_earlier_registrations() {
+        let mut tree = BkTree::new(HashAlg::Gradient, HashConfig::default());
+        assert!(tree.insert("a", &hash(0b0000)));
+        assert!(tree.insert("b", &hash(0b0001)));
+        assert!(!tree.insert("b", &hash(0b0001)));
+        assert!(tree.insert("a", &hash(0b0111)));
+        assert_eq!(keys(tree.find(&hash(0b0000), 1)), ["b"]);
+        assert_eq!(keys(tree.find(&hash(0b0111), 0)), ["a"]);
+    }
+
+    #[test]
+    fn corrupt_indexes_are_refused() {
+        let mut tree = BkTree::new(HashAlg::Gradient, HashConfig::default());
+        tree.insert_new("a", &hash(0));
+        tree.insert_new("b", &hash(1));
+        tree.insert_new("c", &hash(3));
+        assert!(tree.check().is_ok());
+        let valid = serde_json::to_value(&tree).unwrap();
+
+        let corrupt = |path: &str, value: serde_json::Value| {
+            let mut json = valid.clone();
+            *json.pointer_mut(path).unwrap() = value;
+            let tree: BkTree = serde_json::from_value(json).unwrap();
+            tree.check().unwrap_err()
+        };
+        let err = corrupt("/nodes/1/hash_base64", "not base64!".into());
+        assert_eq!(err.code(), "invalid_index");
+        let short = ImageHash::<Box<[u8]>>::from_bytes(&[1, 0, 0, 0])
+            .unwrap()
+            .to_base64();
+        let err = corrupt("/nodes/2/hash_base64", short.into());
+        assert_eq!(err.code(), "invalid_index");
+        let err = corrupt("/nodes/1/children", serde_json::json!([[1, 0]]));
+        assert_eq!(err.code(), "invalid_index");
+        let err = corrupt("/nodes/0/children", serde_json::json!([[1, 3]]));
+        assert_eq!(err.code(), "invalid_index");
+    }
+
+    #[test]
+    fn trees_of_new_keys_find_everything_in_range() {
+        let mut tree = BkTree::new(HashAlg::Gradient, HashConfig::default());
+        let hashes: Vec<(String, ImageHash)> = (0..64u8)
+            .map(|i| (i.to_string(), hash(i.wrapping_mul(37))))
+            .collect();
+        for (key, hash) in &hashes {
+            tree.insert_new(key, hash);
+        }
+        for i in 0..=255u8 {
+            let query = hash(i);
+            let mut expected: Vec<String> = hashes
+                .iter()
+                .filter(|(_, hash)| hash.dist(&query) <= 2)
+                .map(|(key, _)| key.clone())
+                .collect();
+            expected.sort();
+            let mut found = keys(tree.find(&query, 2));
+            found.sort();
+            assert_eq!(found, expected);
+        }
+    }
+}
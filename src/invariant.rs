@@ -0,0 +1,165 @@
+//! Hashing that survives rotating and mirroring an image.
+//!
+//! A perceptual hash changes completely when its image is turned by 90
+//! degrees or mirrored, so re-uploads disguised that way go unnoticed. The
+//! image is hashed in each of its eight rotations and mirrorings instead,
+//! and either the smallest of those hashes is kept, which is the same for
+//! all eight, or distances are taken to the closest of them.
+
+use image::DynamicImage;
+use image_hasher::{Hasher, ImageHash};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// One of the eight rotations and mirrorings of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transform {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipH,
+    FlipV,
+    /// Mirrored along the top-left to bottom-right diagonal.
+    Transpose,
+    /// Mirrored along the top-right to bottom-left diagonal.
+    Transverse,
+}
+
+impl Transform {
+    pub const ALL: [Transform; 8] = [
+        Transform::Identity,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::FlipH,
+        Transform::FlipV,
+        Transform::Transpose,
+        Transform::Transverse,
+    ];
+
+    /// `img` transformed, borrowed as is for [`Transform::Identity`].
+    pub fn apply(self, img: &DynamicImage) -> Cow<'_, DynamicImage> {
+        Cow::Owned(match self {
+            Transform::Identity => return Cow::Borrowed(img),
+            Transform::Rotate90 => img.rotate90(),
+            Transform::Rotate180 => img.rotate180(),
+            Transform::Rotate270 => img.rotate270(),
+            Transform::FlipH => img.fliph(),
+            Transform::FlipV => img.flipv(),
+            Transform::Transpose => img.rotate90().fliph(),
+            Transform::Transverse => img.rotate270().fliph(),
+        })
+    }
+}
+
+/// What an invariant hash reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Invariance {
+    /// The smallest of the eight hashes, the same for every rotation and
+    /// mirroring of an image.
+    Canonical,
+    /// The hash of every transform, keeping the untransformed one as the
+    /// main hash.
+    All,
+}
+
+/// For each of `hashers`, the hash of every transform of `img`, in
+/// [`Transform::ALL`] order.
+///
+/// Each transform is hashed with every hasher and dropped before the next
+/// one is made, so that a large image isn't held eight times over.
+pub fn hash_transforms(img: &DynamicImage, hashers: &[Hasher]) -> Vec<Vec<(Transform, ImageHash)>> {
+    let mut hashes: Vec<Vec<(Transform, ImageHash)>> = hashers
+        .iter()
+        .map(|_| Vec::with_capacity(Transform::ALL.len()))
+        .collect();
+    for transform in Transform::ALL {
+        let transformed = transform.apply(img);
+        for (hasher, hashes) in hashers.iter().zip(&mut hashes) {
+            hashes.push((transform, hasher.hash_image(transformed.as_ref())));
+        }
+    }
+    hashes
+}
+
+/// The hash of every transform of `img`, in [`Transform::ALL`] order.
+pub fn transform_hashes(img: &DynamicImage, hasher: &Hasher) -> Vec<(Transform, ImageHash)> {
+    let mut hashes = hash_transforms(img, std::slice::from_ref(hasher));
+    hashes.pop().expect("there are hashes for every hasher")
+}
+
+/// The smallest of `hashes` by their bytes.
+///
+/// # Panics
+///
+/// If `hashes` is empty.
+pub fn canonical(hashes: &[(Transform, ImageHash)]) -> &ImageHash {
+    hashes
+        .iter()
+        .map(|(_, hash)| hash)
+        .min_by(|a, b| a.as_bytes().cmp(b.as_bytes()))
+        .expect("there is a hash for every transform")
+}
+
+/// The one of `hashes` closest to `other`, and its distance.
+///
+/// # Panics
+///
+/// If `hashes` is empty.
+pub fn closest<'a>(
+    hashes: &'a [(Transform, ImageHash)],
+    other: &ImageHash,
+) -> (&'a (Transform, ImageHash), u32) {
+    hashes
+        .iter()
+        .map(|entry| (entry, entry.1.dist(other)))
+        .min_by_key(|&(_, distance)| distance)
+        .expect("there is a hash for every transform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use image_hasher::{HashAlg, HasherConfig};
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(48, 32, |x, y| {
+            Rgb([(x * 5) as u8, (y * 7) as u8, ((x * y) % 256) as u8])
+        }))
+    }
+
+    #[test]
+    fn every_hasher_sees_every_transform() {
+        let img = image();
+        let hashers: Vec<Hasher> = [HashAlg::Gradient, HashAlg::Mean]
+            .into_iter()
+            .map(|algo| HasherConfig::new().hash_alg(algo).to_hasher())
+            .collect();
+        let by_hasher = hash_transforms(&img, &hashers);
+        assert_eq!(by_hasher.len(), 2);
+        for (hasher, hashes) in hashers.iter().zip(&by_hasher) {
+            let expected: Vec<(Transform, ImageHash)> = Transform::ALL
+                .into_iter()
+                .map(|t| (t, hasher.hash_image(t.apply(&img).as_ref())))
+                .collect();
+            assert_eq!(hashes, &expected);
+        }
+        assert_eq!(transform_hashes(&img, &hashers[1]), by_hasher[1]);
+    }
+
+    #[test]
+    fn canonical_hashes_survive_rotating_and_mirroring() {
+        let img = image();
+        let hasher = HasherConfig::new().to_hasher();
+        let expected = canonical(&transform_hashes(&img, &hasher)).clone();
+        for transform in Transform::ALL {
+            let turned = transform.apply(&img).into_owned();
+            let hashes = transform_hashes(&turned, &hasher);
+            assert_eq!(canonical(&hashes), &expected, "{transform:?}");
+        }
+    }
+}
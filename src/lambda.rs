@@ -0,0 +1,1163 @@
+//! The Lambda function: its payloads, responses and handlers.
+
+use crate::cache::{CacheConfig, CacheKey, CachedResult, ResultCache};
+use crate::compare::{self, CompareRequest, CompareResponse};
+use crate::config::{env_millis, env_or, env_parse};
+use crate::digest::Digests;
+use crate::events::{
+    HttpEvent, HttpResponse, S3Event, SqsBatchItemFailure, SqsBatchResponse, SqsEvent,
+};
+use crate::frames::{self, FrameHashes, FrameOptions};
+use crate::hash::{decode_image_timed, hash_image, DecodeLimits, HashResult, HashSettings};
+use crate::http;
+use crate::index::{HashIndex, IndexMatch};
+use crate::invariant::Invariance;
+use crate::metrics;
+use crate::options::{HashConfig, HashOptions};
+use crate::orientation::{read_orientation, Orientation};
+use crate::s3::{ObjectVersion, RetryPolicy, S3Location};
+use crate::source::{fetch_image, FetchedImage, ImageSource};
+use crate::timings::{self, Timings};
+use crate::web::{parse_url, UrlConfig, UrlFetcher};
+use crate::writeback::{self, WriteBackMode, WriteBackStatus};
+use crate::{ErrorObject, TypedError};
+use base64::Engine;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use image_hasher::HashAlg;
+use lambda_runtime::{Context, LambdaEvent};
+use reqwest::Url;
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{Instrument, Span};
+
+#[derive(Default, Deserialize)]
+pub struct Request {
+    /// Either an object key, or a full `s3://bucket/key` URI.
+    pub path: Option<String>,
+    /// The encoded image itself in standard base64, instead of `path`.
+    pub data_base64: Option<String>,
+    /// An HTTP(S) URL to download the image from, instead of `path`.
+    pub url: Option<String>,
+    /// The encoded image itself, for callers that already hold it, such as
+    /// HTTP uploads. Never read from a payload.
+    #[serde(skip)]
+    pub data: Option<Bytes>,
+    /// Bucket to read from, overriding the `BUCKET_NAME` default.
+    pub bucket: Option<String>,
+    pub algo: Option<HashAlg>,
+    /// Several algorithms to hash the same image with, instead of `algo`.
+    pub algos: Option<Vec<HashAlg>>,
+    #[serde(flatten)]
+    pub options: HashOptions,
+    /// Turn the image upright as its EXIF orientation says before hashing,
+    /// unless `false`.
+    pub auto_orient: Option<bool>,
+    /// Hash every rotation and mirroring of the image as well, and look it
+    /// up by the closest of them.
+    pub invariant: Option<Invariance>,
+    /// Hash the frames of an animated image too, as picked by these options.
+    pub frames: Option<FrameOptions>,
+    /// Look up registered hashes within this many bits in the hash index.
+    pub max_distance: Option<u32>,
+    /// Register the image in the hash index, after any lookup.
+    #[serde(default)]
+    pub register: bool,
+    /// Where to write the hash back to, overriding the `WRITE_BACK` default.
+    pub write_back: Option<WriteBackMode>,
+}
+
+impl Request {
+    /// The algorithms to hash with; see [`select_algos`].
+    pub fn algos(&self) -> Result<Vec<HashAlg>, TypedError> {
+        select_algos(self.algo, self.algos.as_deref())
+    }
+
+    /// Where the image comes from, which is one of `path`, `data_base64`,
+    /// `url` and `data`. Inline images are refused over
+    /// `config.max_inline_bytes`.
+    pub fn input(&self, config: &Config) -> Result<ImageInput, TypedError> {
+        let max_bytes = config.max_inline_bytes;
+        match (&self.path, &self.data_base64, &self.url, &self.data) {
+            (Some(path), None, None, None) => Ok(ImageInput::S3(S3Location::resolve(
+                path,
+                self.bucket.as_deref(),
+                config.default_bucket.as_deref(),
+            )?)),
+            (None, Some(data), None, None) => {
+                decode_inline(data, max_bytes).map(ImageInput::Inline)
+            }
+            (None, None, Some(url), None) => parse_url(url).map(ImageInput::Url),
+            (None, None, None, Some(data)) if data.len() > max_bytes => Err(
+                TypedError::InvalidRequest(format!("images must be at most {max_bytes} bytes")),
+            ),
+            (None, None, None, Some(data)) => Ok(ImageInput::Inline(data.clone())),
+            _ => Err(TypedError::InvalidRequest(
+                "exactly one of `path`, `data_base64` and `url` must be given".to_string(),
+            )),
+        }
+    }
+
+    /// The validated algorithms and hasher settings of the request.
+    pub fn settings(&self) -> Result<HashSettings, TypedError> {
+        let algos = self.algos()?;
+        let config = self.options.resolve(&algos)?;
+        Ok(HashSettings {
+            algos,
+            config,
+            invariance: self.invariant,
+        })
+    }
+
+    /// Everything about the request that changes its result for a given
+    /// object, to cache results by. Write-backs happen on every request, so
+    /// they aren't part of it.
+    fn cache_fingerprint(&self, settings: &HashSettings, max_frames: u32) -> Value {
+        json!({
+            "algos": settings.algos,
+            "config": settings.config,
+            "invariance": settings.invariance,
+            "auto_orient": self.auto_orient.unwrap_or(true),
+            "frames": self.frames,
+            "max_frames": max_frames,
+        })
+    }
+}
+
+/// The image a [`Request`] is about.
+pub enum ImageInput {
+    S3(S3Location),
+    /// Image bytes sent along with the request.
+    Inline(Bytes),
+    Url(Url),
+}
+
+/// Decodes `data_base64`, refusing anything over `max_bytes` once decoded.
+///
+/// A `data:<type>;base64,` prefix, as browsers put in front of files they
+/// read, is skipped.
+fn decode_inline(data: &str, max_bytes: usize) -> Result<Bytes, TypedError> {
+    let data = data
+        .strip_prefix("data:")
+        .and_then(|url| url.split_once(";base64,"))
+        .map_or(data, |(_, data)| data);
+    let too_large = || {
+        TypedError::InvalidRequest(format!(
+            "`data_base64` must decode to at most {max_bytes} bytes"
+        ))
+    };
+    // the estimate is at most 2 bytes over, so this rejects huge payloads
+    // without decoding them first.
+    if base64::decoded_len_estimate(data.len()).saturating_sub(2) > max_bytes {
+        return Err(too_large());
+    }
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| TypedError::InvalidRequest(format!("invalid `data_base64`: {e}")))?;
+    if bytes.len() > max_bytes {
+        return Err(too_large());
+    }
+    Ok(Bytes::from(bytes))
+}
+
+/// The algorithms to hash with given a request's `algo` and `algos`, in the
+/// requested order and without duplicates, defaulting to `Gradient`.
+pub fn select_algos(
+    algo: Option<HashAlg>,
+    algos: Option<&[HashAlg]>,
+) -> Result<Vec<HashAlg>, TypedError> {
+    match (algo, algos) {
+        (Some(_), Some(_)) => Err(TypedError::InvalidRequest(
+            "only one of `algo` and `algos` can be given".to_string(),
+        )),
+        (_, Some([])) => Err(TypedError::InvalidRequest(
+            "`algos` must not be empty".to_string(),
+        )),
+        (_, Some(algos)) => {
+            let mut unique = Vec::with_capacity(algos.len());
+            for &algo in algos {
+                if !unique.contains(&algo) {
+                    unique.push(algo);
+                }
+            }
+            Ok(unique)
+        }
+        (algo, None) => Ok(vec![algo.unwrap_or(HashAlg::Gradient)]),
+    }
+}
+
+/// Any payload the function can be invoked with.
+///
+/// The kind of payload is told from its shape first, and only then is it read
+/// as that kind, so a malformed payload fails with the error of the kind it
+/// was meant to be.
+pub enum Event {
+    /// An S3 event notification, hashing each object in its records.
+    S3(S3Event),
+    /// A batch of SQS messages whose bodies are each a [`Request`].
+    Sqs(SqsEvent),
+    /// An HTTP request from API Gateway or a function URL; see [`http`].
+    Http(HttpEvent),
+    /// A comparison of two images, wrapped as `{"compare": {..}}`.
+    Compare { compare: CompareRequest },
+    /// A direct invocation with a single [`Request`].
+    Request(Request),
+}
+
+impl<'de> Deserialize<'de> for Event {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        fn read<T: DeserializeOwned, E: de::Error>(what: &str, value: Value) -> Result<T, E> {
+            serde_json::from_value(value).map_err(|e| E::custom(format!("invalid {what}: {e}")))
+        }
+
+        let mut value = Value::deserialize(deserializer)?;
+        if let Some(records) = value.get("Records") {
+            let source = records.get(0).map(|record| record.get("eventSource"));
+            return match source {
+                // an empty batch can't say where it came from, and has
+                // nothing to hash either way.
+                None => read("S3 event", value).map(Event::S3),
+                Some(Some(Value::String(source))) if source == "aws:s3" => {
+                    read("S3 event", value).map(Event::S3)
+                }
+                Some(Some(Value::String(source))) if source == "aws:sqs" => {
+                    read("SQS event", value).map(Event::Sqs)
+                }
+                Some(Some(source)) => Err(de::Error::custom(format!(
+                    "unsupported event source {source}"
+                ))),
+                Some(None) => Err(de::Error::custom("records have no `eventSource`")),
+            };
+        }
+        if value.get("httpMethod").is_some() {
+            return read("HTTP request", value).map(|event| Event::Http(HttpEvent::V1(event)));
+        }
+        if value.get("requestContext").is_some() {
+            return read("HTTP request", value).map(|event| Event::Http(HttpEvent::V2(event)));
+        }
+        if let Some(compare) = value.get_mut("compare") {
+            return read("compare request", compare.take())
+                .map(|compare| Event::Compare { compare });
+        }
+        read("request", value).map(Event::Request)
+    }
+}
+
+/// Which [`ImageSource`] images are read from. Only reading images goes
+/// through it; everything the function writes goes to S3.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceConfig {
+    S3,
+    /// A directory holding one subdirectory per bucket.
+    Local(PathBuf),
+}
+
+/// Settings read from the environment once per cold start.
+#[derive(Debug)]
+pub struct Config {
+    /// The default bucket for requests that carry neither a `bucket` nor
+    /// an `s3://` URI.
+    pub default_bucket: Option<String>,
+    /// How many SQS messages of a batch are hashed at the same time.
+    pub sqs_concurrency: usize,
+    /// Where the hash index is persisted, if lookups are enabled.
+    pub index_location: Option<S3Location>,
+    /// The algorithm a new hash index is built with; an existing one keeps
+    /// whatever it was built with.
+    pub index_algo: HashAlg,
+    /// The hasher settings a new hash index is built with.
+    pub index_config: HashConfig,
+    /// Where hashes are written back to unless a request says otherwise.
+    pub write_back: WriteBackMode,
+    /// Key prefix of the sidecar objects written in [`WriteBackMode::Sidecar`].
+    pub write_back_prefix: String,
+    /// Where images are read from. The hash index, write-backs and results
+    /// cached in S3 always use S3, whatever the source.
+    pub source: SourceConfig,
+    /// Largest image, in bytes, accepted inline as `data_base64` or as an
+    /// HTTP upload.
+    pub max_inline_bytes: usize,
+    /// Limits on downloading images from a request's `url`.
+    pub url: UrlConfig,
+    /// Limits on downloading and decoding any image.
+    pub limits: DecodeLimits,
+    /// How S3 downloads of images are retried.
+    pub retry: RetryPolicy,
+    /// Time kept back from the invocation's deadline for reporting a result,
+    /// so retries stop before Lambda times the invocation out.
+    pub deadline_margin: Duration,
+    /// Most frames of an animation hashed, whatever a request asks for.
+    pub max_frames: u32,
+    /// Where results of hashing S3 objects are cached.
+    pub cache: CacheConfig,
+}
+
+impl Default for Config {
+    /// The settings of a function with no environment variables set.
+    fn default() -> Self {
+        Config {
+            default_bucket: None,
+            sqs_concurrency: 4,
+            index_location: None,
+            index_algo: HashAlg::Gradient,
+            index_config: HashConfig::default(),
+            write_back: WriteBackMode::None,
+            write_back_prefix: "image-hashes/".to_string(),
+            source: SourceConfig::S3,
+            max_inline_bytes: 4 * 1024 * 1024,
+            url: UrlConfig::default(),
+            limits: DecodeLimits::default(),
+            retry: RetryPolicy::default(),
+            deadline_margin: Duration::from_secs(1),
+            max_frames: 100,
+            cache: CacheConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let config = Config::default();
+        let sqs_concurrency = env_or("SQS_CONCURRENCY", config.sqs_concurrency).max(1);
+        let default_bucket = std::env::var("BUCKET_NAME").ok();
+        let index_location = std::env::var("INDEX_KEY").ok().map(|key| S3Location {
+            bucket: std::env::var("INDEX_BUCKET")
+                .ok()
+                .or_else(|| default_bucket.clone())
+                .expect("INDEX_BUCKET or BUCKET_NAME must be set to use INDEX_KEY"),
+            key,
+        });
+        let index_algo = env_parse("INDEX_ALGO", "an algorithm such as `Gradient`", |v| {
+            serde_json::from_value(Value::String(v.to_string()))
+        })
+        .unwrap_or(config.index_algo);
+        let index_options = HashOptions {
+            hash_width: env_parse("INDEX_HASH_WIDTH", "an integer", str::parse),
+            hash_height: env_parse("INDEX_HASH_HEIGHT", "an integer", str::parse),
+            preproc_dct: env_parse("INDEX_PREPROC_DCT", "`true` or `false`", str::parse)
+                .unwrap_or(config.index_config.preproc_dct),
+            ..HashOptions::default()
+        };
+        let index_config = index_options
+            .resolve(&[index_algo])
+            .unwrap_or_else(|err| panic!("INDEX_* settings are invalid: {err}"));
+        let cache_location = std::env::var("CACHE_PREFIX").ok().map(|prefix| S3Location {
+            bucket: std::env::var("CACHE_BUCKET")
+                .ok()
+                .or_else(|| default_bucket.clone())
+                .expect("CACHE_BUCKET or BUCKET_NAME must be set to use CACHE_PREFIX"),
+            key: prefix,
+        });
+        let write_back = std::env::var("WRITE_BACK")
+            .map(|v| {
+                v.parse()
+                    .expect("WRITE_BACK must be `none`, `tags` or `sidecar`")
+            })
+            .unwrap_or(config.write_back);
+        let write_back_prefix =
+            std::env::var("WRITE_BACK_PREFIX").unwrap_or(config.write_back_prefix);
+        let source = match std::env::var("IMAGE_SOURCE").as_deref() {
+            Err(_) => config.source,
+            Ok("s3") => SourceConfig::S3,
+            Ok("local") => SourceConfig::Local(
+                std::env::var("IMAGE_SOURCE_ROOT")
+                    .expect("IMAGE_SOURCE_ROOT must be set when IMAGE_SOURCE is `local`")
+                    .into(),
+            ),
+            Ok(other) => panic!("IMAGE_SOURCE must be `s3` or `local`, got `{other}`"),
+        };
+        let (limits, retry) = (config.limits, config.retry);
+        Config {
+            default_bucket,
+            sqs_concurrency,
+            index_location,
+            index_algo,
+            index_config,
+            write_back,
+            write_back_prefix,
+            source,
+            max_inline_bytes: env_or("MAX_INLINE_BYTES", config.max_inline_bytes),
+            url: UrlConfig::from_env(),
+            limits: DecodeLimits {
+                max_object_bytes: env_or("MAX_OBJECT_BYTES", limits.max_object_bytes),
+                max_width: env_or("MAX_IMAGE_WIDTH", limits.max_width),
+                max_height: env_or("MAX_IMAGE_HEIGHT", limits.max_height),
+                max_alloc: env_or("MAX_DECODE_BYTES", limits.max_alloc),
+            },
+            retry: RetryPolicy {
+                max_attempts: env_or("S3_MAX_ATTEMPTS", retry.max_attempts).max(1),
+                base_delay: env_millis("S3_RETRY_BASE_MS", retry.base_delay),
+                max_delay: env_millis("S3_RETRY_MAX_MS", retry.max_delay),
+            },
+            deadline_margin: env_millis("DEADLINE_MARGIN_MS", config.deadline_margin),
+            max_frames: env_or("MAX_FRAMES", config.max_frames).max(1),
+            cache: CacheConfig {
+                entries: env_or("CACHE_ENTRIES", config.cache.entries),
+                location: cache_location,
+            },
+        }
+    }
+}
+
+/// Everything set up once per cold start and shared across invocations.
+pub struct State {
+    pub s3_client: aws_sdk_s3::Client,
+    pub source: Box<dyn ImageSource>,
+    pub url_fetcher: UrlFetcher,
+    pub config: Config,
+    pub index: Option<HashIndex>,
+    pub cache: Option<ResultCache>,
+}
+
+impl State {
+    /// The hash index if `needed`, failing if none is configured.
+    pub fn index_for(&self, needed: bool) -> Result<Option<&HashIndex>, TypedError> {
+        match &self.index {
+            Some(index) if needed => Ok(Some(index)),
+            None if needed => Err(TypedError::InvalidRequest(
+                "no hash index is configured".to_string(),
+            )),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Response {
+    #[serde(flatten)]
+    pub hash: HashResult,
+    /// Near-duplicates from the hash index, when `max_distance` was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matches: Option<Vec<IndexMatch>>,
+    /// Outcome of writing the hash back to S3, if enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_back: Option<WriteBackStatus>,
+    /// Why writing back failed, when `write_back` is `failed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_back_error: Option<ErrorObject>,
+    /// How many attempts downloading the image from S3 took.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+    /// Where the time handling the image went.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    /// The EXIF orientation the image was turned upright from, if it had one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<Orientation>,
+    /// Cryptographic digests of the encoded image and of its pixels, to find
+    /// exact duplicates.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digests: Option<Digests>,
+    /// The hashes of the frames of an animation, when `frames` was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frames: Option<FrameHashes>,
+    /// Whether the result came from the result cache, when it was checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached: Option<bool>,
+}
+
+impl From<CachedResult> for Response {
+    fn from(cached: CachedResult) -> Self {
+        Response {
+            orientation: cached.orientation,
+            digests: cached.digests,
+            frames: cached.frames,
+            cached: Some(true),
+            ..Response::from(cached.hash)
+        }
+    }
+}
+
+impl From<HashResult> for Response {
+    fn from(hash: HashResult) -> Self {
+        Response {
+            hash,
+            matches: None,
+            write_back: None,
+            write_back_error: None,
+            attempts: None,
+            timings: None,
+            orientation: None,
+            digests: None,
+            frames: None,
+            cached: None,
+        }
+    }
+}
+
+impl Response {
+    /// Records the outcome of writing the hash back, keeping the hash even if
+    /// writing failed.
+    fn set_write_back(&mut self, result: Result<Option<WriteBackStatus>, TypedError>) {
+        match result {
+            Ok(status) => self.write_back = status,
+            Err(err) => {
+                self.write_back = Some(WriteBackStatus::Failed);
+                self.write_back_error = Some(ErrorObject::from(err));
+            }
+        }
+    }
+}
+
+/// The outcome of hashing one record of an S3 event.
+#[derive(Debug, Serialize)]
+pub struct RecordResult {
+    pub bucket: String,
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Response>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorObject>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecordsResponse {
+    pub records: Vec<RecordResult>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Output {
+    Hash(Box<Response>),
+    Records(RecordsResponse),
+    Batch(SqsBatchResponse),
+    Compare(CompareResponse),
+    Http(HttpResponse),
+}
+
+#[tracing::instrument(skip(state, event), fields(req_id = %event.context.request_id))]
+pub async fn handle_event(state: &State, event: LambdaEvent<Event>) -> Result<Output, ErrorObject> {
+    let deadline = invocation_deadline(&event.context, state.config.deadline_margin);
+    match event.payload {
+        Event::Request(request) => {
+            let key = request.path.clone().or_else(|| request.url.clone());
+            put_object(state, request, deadline)
+                .await
+                .map(|response| Output::Hash(Box::new(response)))
+                .map_err(|err| match key {
+                    Some(key) => ErrorObject::from(err).or_key(key),
+                    None => ErrorObject::from(err),
+                })
+        }
+        Event::S3(s3_event) => Ok(Output::Records(
+            hash_s3_event(state, s3_event, deadline).await,
+        )),
+        Event::Compare { compare } => compare::compare(state, compare, deadline)
+            .await
+            .map(Output::Compare)
+            .map_err(ErrorObject::from),
+        Event::Sqs(sqs_event) => Ok(Output::Batch(
+            hash_sqs_event(state, sqs_event, deadline).await,
+        )),
+        Event::Http(http_event) => Ok(Output::Http(
+            http::handle_http(state, http_event, deadline).await,
+        )),
+    }
+}
+
+/// When work on an invocation should stop, `margin` before Lambda times it
+/// out. `None` when the runtime gave no deadline.
+fn invocation_deadline(context: &Context, margin: Duration) -> Option<Instant> {
+    if context.deadline == 0 {
+        return None;
+    }
+    let deadline = UNIX_EPOCH + Duration::from_millis(context.deadline);
+    let remaining = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    Some(Instant::now() + remaining.saturating_sub(margin))
+}
+
+/// Hashes the image of a single request. S3 downloads are retried until
+/// `deadline`, if given, and URL downloads are cut off at it.
+pub async fn put_object(
+    state: &State,
+    request: Request,
+    deadline: Option<Instant>,
+) -> Result<Response, TypedError> {
+    hash_request(state, request, deadline)
+        .instrument(timings::span())
+        .await
+}
+
+async fn hash_request(
+    state: &State,
+    request: Request,
+    deadline: Option<Instant>,
+) -> Result<Response, TypedError> {
+    tracing::info!("handling a request");
+    let start = Instant::now();
+
+    let input = request.input(&state.config)?;
+    let settings = request.settings()?;
+    if let Some(frames) = &request.frames {
+        frames.validate()?;
+    }
+    let uses_index = request.max_distance.is_some() || request.register;
+    let index = state.index_for(uses_index)?;
+    // what the image is registered under in the index, and where its hash
+    // can be written back to.
+    let (key, location) = match &input {
+        ImageInput::S3(location) => (Some(location.to_string()), Some(location)),
+        ImageInput::Url(url) => (Some(url.to_string()), None),
+        ImageInput::Inline(_) => (None, None),
+    };
+    if request.register && key.is_none() {
+        return Err(TypedError::InvalidRequest(
+            "`register` needs a `path` or a `url`".to_string(),
+        ));
+    }
+    let write_back = match (location, request.write_back) {
+        (Some(_), mode) => mode.unwrap_or(state.config.write_back),
+        (None, None | Some(WriteBackMode::None)) => WriteBackMode::None,
+        (None, Some(_)) => {
+            return Err(TypedError::InvalidRequest(
+                "`write_back` needs a `path`".to_string(),
+            ))
+        }
+    };
+    writeback::validate(write_back, settings.algos[0], &settings.config)?;
+
+    // results for S3 objects are cached, unless the image itself is needed
+    // to look it up in or register it with the index.
+    let cache = match (&state.cache, location) {
+        (Some(cache), Some(location)) if !uses_index => {
+            let fingerprint = request.cache_fingerprint(&settings, state.config.max_frames);
+            Some((cache, location, fingerprint))
+        }
+        _ => None,
+    };
+    let mut cache_timings = Timings::default();
+    if let Some((cache, location, fingerprint)) = &cache {
+        let head_start = Instant::now();
+        let version = state.source.head(location).await?;
+        Timings::add(&mut cache_timings.request, head_start);
+        let cached = match CacheKey::new(location, &version, fingerprint) {
+            Some(key) => cache.get(&state.s3_client, &key).await,
+            None => None,
+        };
+        metrics::cache_checked(settings.algos[0], cached.is_some());
+        if let Some(cached) = cached {
+            let mut response = Response::from(cached);
+            // the hash may have been cached by a request that didn't write it
+            // back, or its tags or sidecar removed since.
+            let result = writeback::write_back(
+                &state.s3_client,
+                write_back,
+                &state.config.write_back_prefix,
+                location,
+                &version,
+                &response.hash,
+            )
+            .await;
+            response.set_write_back(result);
+            Timings::add(&mut cache_timings.total, start);
+            cache_timings.record(&Span::current());
+            tracing::info!(
+                bucket = %location.bucket,
+                key = %location.key,
+                "cached result returned"
+            );
+            response.timings = Some(cache_timings);
+            return Ok(response);
+        }
+    }
+
+    let limits = &state.config.limits;
+    let mut fetched = match &input {
+        ImageInput::S3(location) => {
+            fetch_image(state.source.as_ref(), location, limits, deadline).await?
+        }
+        ImageInput::Url(url) => state.url_fetcher.fetch_image(url, limits, deadline).await?,
+        ImageInput::Inline(bytes) => {
+            let mut timings = Timings {
+                bytes: bytes.len() as u64,
+                ..Timings::default()
+            };
+            FetchedImage {
+                image: decode_image_timed(bytes, limits, &mut timings)?,
+                bytes: bytes.clone(),
+                version: ObjectVersion::default(),
+                attempts: 1,
+                timings,
+                orientation: read_orientation(bytes),
+            }
+        }
+    };
+    let orientation = fetched.auto_orient(request.auto_orient.unwrap_or(true));
+    let img = &fetched.image;
+    let mut response = Response::from(hash_image(img, &settings));
+    response.orientation = orientation;
+    response.digests = Some(Digests::new(&fetched.bytes, img));
+    if location.is_some() {
+        response.attempts = Some(fetched.attempts);
+    }
+    let mut timings = Timings {
+        hash: response.hash.time_elapsed,
+        ..fetched.timings
+    };
+    timings.request += cache_timings.request;
+
+    if let Some(index) = index {
+        let hash = index.hash_image(img).await;
+        if let Some(max_distance) = request.max_distance {
+            let mut matches = match settings.invariance {
+                Some(_) => index.find_invariant(img, max_distance).await,
+                None => index.find(&hash, max_distance).await,
+            };
+            matches.retain(|m| Some(&m.key) != key.as_ref());
+            response.matches = Some(matches);
+        }
+        if let (true, Some(key)) = (request.register, &key) {
+            index.insert(&state.s3_client, key, &hash).await?;
+        }
+    }
+    if let Some(options) = &request.frames {
+        let lookup = match (index, request.max_distance) {
+            (Some(index), Some(_)) => Some(index.hasher().await),
+            _ => None,
+        };
+        let mut frames = frames::hash_frames(
+            &fetched.bytes,
+            img,
+            limits,
+            orientation,
+            &settings,
+            options,
+            state.config.max_frames,
+            lookup.as_ref(),
+            &mut timings,
+        )?;
+        if let (Some(index), Some(max_distance)) = (index, request.max_distance) {
+            find_frames(index, &mut frames, max_distance, key.as_deref()).await;
+        }
+        response.frames = Some(frames);
+    }
+
+    if let Some(location) = location {
+        let result = writeback::write_back(
+            &state.s3_client,
+            write_back,
+            &state.config.write_back_prefix,
+            location,
+            &fetched.version,
+            &response.hash,
+        )
+        .await;
+        response.set_write_back(result);
+    }
+
+    if let Some((cache, location, fingerprint)) = cache {
+        response.cached = Some(false);
+        if let Some(key) = CacheKey::new(location, &fetched.version, &fingerprint) {
+            let result = CachedResult {
+                hash: response.hash.clone(),
+                orientation: response.orientation,
+                digests: response.digests.clone(),
+                frames: response.frames.clone(),
+            };
+            cache.put(&state.s3_client, key, result).await;
+        }
+    }
+
+    Timings::add(&mut timings.total, start);
+    timings.record(&Span::current());
+    tracing::info!("image hashed");
+    metrics::image_hashed(&response.hash, &timings);
+    response.timings = Some(timings);
+    Ok(response)
+}
+
+/// Looks up every frame hashed for the index within `max_distance` bits,
+/// leaving out the image's own `key`.
+async fn find_frames(
+    index: &HashIndex,
+    frames: &mut FrameHashes,
+    max_distance: u32,
+    key: Option<&str>,
+) {
+    for frame in &mut frames.frames {
+        if let Some(hash) = &frame.lookup_hash {
+            let mut matches = index.find(hash, max_distance).await;
+            matches.retain(|m| Some(m.key.as_str()) != key);
+            frame.matches = Some(matches);
+        }
+    }
+}
+
+/// Hashes every object in an S3 event notification, reporting each record's
+/// outcome separately so one bad upload doesn't hide the others.
+pub async fn hash_s3_event(
+    state: &State,
+    event: S3Event,
+    deadline: Option<Instant>,
+) -> RecordsResponse {
+    tracing::info!(records = event.records.len(), "handling an S3 event");
+
+    let mut records = Vec::with_capacity(event.records.len());
+    for record in event.records {
+        let location = S3Location {
+            bucket: record.s3.bucket.name,
+            key: record.s3.object.decoded_key(),
+        };
+        // a record is hashed as a request for its object with every option
+        // left to its default.
+        let request = Request {
+            path: Some(location.to_string()),
+            ..Request::default()
+        };
+        let result = put_object(state, request, deadline).await;
+        if let Err(err) = &result {
+            tracing::error!(
+                err = %err,
+                bucket = %location.bucket,
+                key = %location.key,
+                event_name = record.event_name.as_deref().unwrap_or_default(),
+                "failed to hash S3 event record"
+            );
+        }
+        let (hash, error) = match result {
+            Ok(response) => (Some(response), None),
+            Err(err) => (
+                None,
+                Some(ErrorObject::from(err).or_key(location.to_string())),
+            ),
+        };
+        records.push(RecordResult {
+            bucket: location.bucket,
+            key: location.key,
+            hash,
+            error,
+        });
+    }
+    RecordsResponse { records }
+}
+
+/// Hashes each message of an SQS batch, at most `config.sqs_concurrency` at
+/// a time, and reports the ids of the ones that failed so that only those
+/// are redriven.
+pub async fn hash_sqs_event(
+    state: &State,
+    event: SqsEvent,
+    deadline: Option<Instant>,
+) -> SqsBatchResponse {
+    tracing::info!(messages = event.records.len(), "handling an SQS batch");
+
+    let batch_item_failures = stream::iter(event.records)
+        .map(|message| async move {
+            let result = match serde_json::from_str::<Request>(&message.body) {
+                Ok(request) => put_object(state, request, deadline).await.map(|_| ()),
+                Err(err) => Err(TypedError::InvalidRequest(format!(
+                    "invalid message body: {err}"
+                ))),
+            };
+            match result.map_err(ErrorObject::from) {
+                Ok(()) => None,
+                Err(err) => {
+                    tracing::error!(
+                        err = %err.message,
+                        code = err.code,
+                        retryable = err.retryable,
+                        message_id = %message.message_id,
+                        "failed to hash SQS message"
+                    );
+                    Some(SqsBatchItemFailure {
+                        item_identifier: message.message_id,
+                    })
+                }
+            }
+        })
+        .buffer_unordered(state.config.sqs_concurrency)
+        .filter_map(|failure| async move { failure })
+        .collect()
+        .await;
+
+    SqsBatchResponse {
+        batch_item_failures,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hash::hash_bytes;
+    use crate::source::MemorySource;
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use sha2::{Digest, Sha256};
+    use std::io::Cursor;
+
+    pub(crate) fn jpeg() -> Vec<u8> {
+        let img = RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 90])
+        });
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut bytes, ImageFormat::Jpeg)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    /// A state with `config` reading from `source`, with an S3 client that
+    /// fails whatever it is asked to do.
+    ///
+    /// `config` is built rather than read from the environment, so that
+    /// settings exported by whoever runs the tests don't change their outcome.
+    pub(crate) fn state(source: MemorySource, config: Config) -> State {
+        let s3_config = aws_sdk_s3::Config::builder()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .build();
+        State {
+            s3_client: aws_sdk_s3::Client::from_conf(s3_config),
+            source: Box::new(source),
+            url_fetcher: UrlFetcher::new(config.url.clone()),
+            config,
+            index: None,
+            cache: None,
+        }
+    }
+
+    fn request(value: Value) -> Request {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn inline_images_are_decoded_and_limited() {
+        let base64 = base64::engine::general_purpose::STANDARD;
+        let data = base64.encode([7; 100]);
+        assert_eq!(decode_inline(&data, 100).unwrap().as_ref(), [7; 100]);
+        let url = format!("data:image/jpeg;base64,{data}");
+        assert_eq!(decode_inline(&url, 100).unwrap().as_ref(), [7; 100]);
+
+        let err = decode_inline(&data, 99).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid request: `data_base64` must decode to at most 99 bytes"
+        );
+        // payloads far over the limit are refused without being decoded.
+        let err = decode_inline(&"!".repeat(1000), 10).unwrap_err();
+        assert!(err.to_string().contains("at most 10 bytes"), "{err}");
+
+        for data in ["not base64!", "data:image/jpeg,/9j/", "QUJD=D"] {
+            let err = decode_inline(data, 100).unwrap_err();
+            assert!(err.to_string().contains("invalid `data_base64`"), "{err}");
+        }
+    }
+
+    #[test]
+    fn algos_are_deduplicated_in_order() {
+        use HashAlg::{Gradient, Mean, Median};
+        assert_eq!(select_algos(None, None).unwrap(), [Gradient]);
+        assert_eq!(select_algos(Some(Mean), None).unwrap(), [Mean]);
+        // the first algorithm listed is the primary one, wherever it repeats.
+        let algos = select_algos(None, Some(&[Median, Mean, Median, Gradient, Mean])).unwrap();
+        assert_eq!(algos, [Median, Mean, Gradient]);
+
+        let err = select_algos(None, Some(&[])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid request: `algos` must not be empty"
+        );
+        let err = select_algos(Some(Mean), Some(&[Mean])).unwrap_err();
+        assert_eq!(err.code(), "invalid_request");
+        // unknown names are refused when the request is read.
+        let unknown = serde_json::from_value::<Request>(json!({"algos": ["Mean", "Foo"]}));
+        let err = unknown.err().unwrap().to_string();
+        assert!(err.contains("unknown variant `Foo`"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn put_object_hashes_images_from_the_source() {
+        let bytes = jpeg();
+        let mut source = MemorySource::new();
+        let location = S3Location {
+            bucket: "photos".to_string(),
+            key: "cat.jpg".to_string(),
+        };
+        source.insert(location, bytes.clone());
+        let state = state(source, Config::default());
+
+        let cat = request(json!({
+            "path": "s3://photos/cat.jpg",
+            "algos": ["Gradient", "Mean"],
+            "frames": {},
+        }));
+        let response = put_object(&state, cat, None).await.unwrap();
+        let settings = HashSettings {
+            algos: vec![HashAlg::Gradient, HashAlg::Mean],
+            ..HashSettings::default()
+        };
+        let expected = hash_bytes(&bytes, &settings).unwrap();
+        assert_eq!(response.hash.hashes, expected.hashes);
+        assert_eq!(response.hash.image_size, (64, 48));
+        assert_eq!(response.attempts, Some(1));
+        assert_eq!(response.write_back, None);
+        let digests = response.digests.unwrap();
+        assert_eq!(digests.file.sha256, hex::encode(Sha256::digest(&bytes)));
+        let frames = response.frames.unwrap();
+        assert_eq!(frames.frames.len(), 1);
+        assert_eq!(frames.aggregate, expected.hashes);
+
+        let missing = request(json!({"path": "s3://photos/dog.jpg"}));
+        let err = put_object(&state, missing, None).await.unwrap_err();
+        assert_eq!(err.code(), "not_found");
+        assert_eq!(err.key(), Some("s3://photos/dog.jpg"));
+    }
+
+    #[tokio::test]
+    async fn cached_results_are_still_written_back() {
+        let mut source = MemorySource::new();
+        let location = S3Location {
+            bucket: "photos".to_string(),
+            key: "cat.jpg".to_string(),
+        };
+        source.insert(location, jpeg());
+        let mut state = state(source, Config::default());
+        state.cache = ResultCache::new(&CacheConfig {
+            entries: 8,
+            location: None,
+        });
+
+        let cat = || request(json!({"path": "s3://photos/cat.jpg", "frames": {}}));
+        let miss = put_object(&state, cat(), None).await.unwrap();
+        assert_eq!(miss.cached, Some(false));
+        assert_eq!(miss.attempts, Some(1));
+        let hit = put_object(&state, cat(), None).await.unwrap();
+        assert_eq!(hit.cached, Some(true));
+        assert_eq!(hit.attempts, None);
+        assert_eq!(hit.hash.hashes, miss.hash.hashes);
+        assert_eq!(hit.digests, miss.digests);
+        assert_eq!(hit.write_back, None);
+
+        let mean = request(json!({"path": "s3://photos/cat.jpg", "algo": "Mean"}));
+        let response = put_object(&state, mean, None).await.unwrap();
+        assert_eq!(response.cached, Some(false));
+
+        // the S3 client fails whatever it is asked, which shows that writing
+        // back was attempted.
+        let tags = request(json!({
+            "path": "s3://photos/cat.jpg",
+            "frames": {},
+            "write_back": "tags",
+        }));
+        let hit = put_object(&state, tags, None).await.unwrap();
+        assert_eq!(hit.cached, Some(true));
+        assert_eq!(hit.write_back, Some(WriteBackStatus::Failed));
+        assert!(hit.write_back_error.is_some());
+    }
+
+    #[tokio::test]
+    async fn s3_records_are_hashed_like_requests() {
+        let bytes = jpeg();
+        let mut source = MemorySource::new();
+        let location = S3Location {
+            bucket: "photos".to_string(),
+            key: "cat photo.jpg".to_string(),
+        };
+        source.insert(location, bytes.clone());
+        let state = state(source, Config::default());
+
+        let event: S3Event = serde_json::from_value(json!({"Records": [
+            {"s3": {"bucket": {"name": "photos"}, "object": {"key": "cat+photo.jpg"}}},
+            {"s3": {"bucket": {"name": "photos"}, "object": {"key": "dog.jpg"}}},
+        ]}))
+        .unwrap();
+        let response = hash_s3_event(&state, event, None).await;
+        let [cat, dog] = &response.records[..] else {
+            panic!("every record is reported");
+        };
+        let hash = cat.hash.as_ref().unwrap();
+        let expected = hash_bytes(&bytes, &HashSettings::default()).unwrap();
+        assert_eq!(hash.hash.hashes, expected.hashes);
+        assert_eq!(hash.attempts, Some(1));
+        assert!(hash.digests.is_some());
+        let err = dog.error.as_ref().unwrap();
+        assert_eq!(err.code, "not_found");
+        assert_eq!(err.key.as_deref(), Some("s3://photos/dog.jpg"));
+    }
+
+    #[tokio::test]
+    async fn only_failed_sqs_messages_are_redriven() {
+        let mut source = MemorySource::new();
+        for key in ["cat.jpg", "cow.jpg"] {
+            let location = S3Location {
+                bucket: "photos".to_string(),
+                key: key.to_string(),
+            };
+            source.insert(location, jpeg());
+        }
+        let state = state(source, Config::default());
+
+        let event: SqsEvent = serde_json::from_value(json!({"Records": [
+            {"messageId": "cat", "body": r#"{"path": "s3://photos/cat.jpg"}"#},
+            {"messageId": "dog", "body": r#"{"path": "s3://photos/dog.jpg"}"#},
+            {"messageId": "junk", "body": "not a request"},
+            {"messageId": "cow", "body": r#"{"path": "s3://photos/cow.jpg", "algo": "Mean"}"#},
+        ]}))
+        .unwrap();
+        let response = hash_sqs_event(&state, event, None).await;
+        let mut failures = serde_json::to_value(response).unwrap()["batchItemFailures"]
+            .as_array()
+            .unwrap()
+            .clone();
+        failures.sort_by_key(|failure| failure.to_string());
+        assert_eq!(
+            failures,
+            [
+                json!({"itemIdentifier": "dog"}),
+                json!({"itemIdentifier": "junk"})
+            ]
+        );
+    }
+
+    fn event(value: Value) -> Result<Event, String> {
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    fn error(value: Value) -> String {
+        match event(value) {
+            Ok(_) => panic!("a malformed event was accepted"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn events_are_routed_by_shape() {
+        let s3 = json!({"Records": [{
+            "eventSource": "aws:s3",
+            "s3": {"bucket": {"name": "b"}, "object": {"key": "k"}}
+        }]});
+        assert!(matches!(event(s3), Ok(Event::S3(_))));
+        let sqs = json!({"Records": [{
+            "eventSource": "aws:sqs", "messageId": "1", "body": "{}"
+        }]});
+        assert!(matches!(event(sqs), Ok(Event::Sqs(_))));
+        let http = json!({"httpMethod": "GET", "path": "/hash"});
+        assert!(matches!(event(http), Ok(Event::Http(HttpEvent::V1(_)))));
+        let compare = json!({"compare": {"path": "a.jpg", "other_path": "b.jpg"}});
+        assert!(matches!(event(compare), Ok(Event::Compare { .. })));
+        assert!(matches!(
+            event(json!({"path": "a.jpg"})),
+            Ok(Event::Request(_))
+        ));
+    }
+
+    #[test]
+    fn malformed_events_keep_their_own_error() {
+        let err = error(json!({"compare": {"other_path": "b.jpg"}}));
+        assert!(err.contains("invalid compare request"), "{err}");
+        assert!(err.contains("path"), "{err}");
+
+        let err = error(json!({"Records": [{
+            "eventSource": "aws:sqs", "messageId": "1"
+        }]}));
+        assert!(err.contains("invalid SQS event"), "{err}");
+        assert!(err.contains("body"), "{err}");
+
+        let err = error(json!({"Records": [{"eventSource": "aws:sns"}]}));
+        assert!(err.contains("unsupported event source"), "{err}");
+    }
+}
@@ -0,0 +1,31 @@
+//! Perceptual hashing of images, as done by the `lambda_image_hash` Lambda
+//! function.
+//!
+//! [`hash_bytes`] hashes an image already in memory, and [`source`] reads
+//! images from S3, a local directory or memory the way the function does, so
+//! other services get bit-identical hashes. The function itself lives in
+//! [`lambda`].
+
+pub mod cache;
+pub mod compare;
+pub mod config;
+pub mod digest;
+pub mod error;
+pub mod events;
+pub mod frames;
+pub mod hash;
+pub mod http;
+pub mod index;
+pub mod invariant;
+pub mod lambda;
+pub mod metrics;
+pub mod options;
+pub mod orientation;
+pub mod s3;
+pub mod source;
+pub mod timings;
+pub mod web;
+pub mod writeback;
+
+pub use error::{ErrorObject, TypedError};
+pub use hash::{hash_bytes, HashResult, HashSettings};
@@ -1,134 +1,78 @@
 use aws_config::BehaviorVersion;
-use aws_sdk_s3::operation::get_object::GetObjectOutput;
-use image::io::Reader as ImageReader;
-use image::GenericImageView;
-use image_hasher::{HashAlg, HasherConfig};
+use lambda_image_hash::cache::ResultCache;
+use lambda_image_hash::index::HashIndex;
+use lambda_image_hash::lambda::{handle_event, Config, Event, SourceConfig, State};
+use lambda_image_hash::metrics::{self, EmfLayer, MetricsConfig};
+use lambda_image_hash::source::{ImageSource, LocalSource, S3Source};
+use lambda_image_hash::web::UrlFetcher;
 use lambda_runtime::{service_fn, Error, LambdaEvent};
-use serde::{Deserialize, Serialize};
-use std::io::Cursor;
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum TypedError {
-    #[error("Failed to retrieve from S3")]
-    S3Get,
-    #[error("Failed to download from S3: `{0}`")]
-    S3Download(String),
-    #[error("Invalid image format `{0}` guessed")]
-    InvalidFormat(String),
-}
-
-#[derive(Deserialize)]
-struct Request {
-    path: String,
-    algo: Option<HashAlg>,
-}
-
-#[derive(Debug, Serialize)]
-struct Response {
-    hash_base64: String,
-    algo: HashAlg,
-    image_size: (u32, u32),
-    time_elapsed: f64,
-}
-
-async fn download_from_s3(
-    s3_client: &aws_sdk_s3::Client,
-    bucket_name: &str,
-    key: &str,
-) -> Result<GetObjectOutput, TypedError> {
-    let response = s3_client
-        .get_object()
-        .bucket(bucket_name)
-        .key(key)
-        .send()
-        .await;
-    match response {
-        Ok(output) => {
-            tracing::info!(
-                key = %key,
-                "data successfully retrieved from S3",
-            );
-            Ok(output)
-        }
-        Err(err) => {
-            tracing::error!(
-                err = %err,
-                key = %key,
-                "failed to retrieve data from S3"
-            );
-            Err(TypedError::S3Get)
-        }
-    }
-}
-
-#[tracing::instrument(skip(s3_client, event), fields(req_id = %event.context.request_id))]
-async fn put_object(
-    s3_client: &aws_sdk_s3::Client,
-    bucket_name: &str,
-    event: LambdaEvent<Request>,
-) -> Result<Response, TypedError> {
-    tracing::info!("handling a request");
-
-    let key = event.payload.path.clone();
-    let response = download_from_s3(s3_client, bucket_name, &key).await?;
-
-    let data = response
-        .body
-        .collect()
-        .await
-        .map_err(|e| TypedError::S3Download(e.to_string()))?;
-
-    let bytes = data.into_bytes();
-
-    // Load image from bytes
-    let img = ImageReader::new(Cursor::new(bytes))
-        .with_guessed_format()
-        .map_err(|e| TypedError::InvalidFormat(e.to_string()))?
-        .decode()
-        .map_err(|e| TypedError::InvalidFormat(e.to_string()))?;
-
-    // get image size
-    let (width, height) = img.dimensions();
-
-    // get hashing timing
-    let algo = event.payload.algo.unwrap_or(HashAlg::Gradient);
-    let hasher = HasherConfig::new().hash_alg(algo).to_hasher();
-    let start = std::time::Instant::now();
-    let hash = hasher.hash_image(&img);
-    let elapsed = start.elapsed();
-
-    Ok(Response {
-        hash_base64: hash.to_base64(),
-        image_size: (width, height),
-        algo,
-        time_elapsed: elapsed.as_secs_f64(),
-    })
-}
+use tracing_subscriber::filter::{filter_fn, LevelFilter};
+use tracing_subscriber::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        // disable printing the name of the module in every log line.
-        .with_target(false)
-        // disabling time is handy because CloudWatch will add the ingestion time.
-        .without_time()
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                // disable printing the name of the module in every log line.
+                .with_target(false)
+                // disabling time is handy because CloudWatch will add the ingestion time.
+                .without_time()
+                // metrics are printed by their own layer, as EMF.
+                .with_filter(filter_fn(|metadata| metadata.target() != metrics::TARGET)),
+        )
+        .with(MetricsConfig::from_env().map(EmfLayer::new))
+        .with(LevelFilter::INFO)
         .init();
 
-    let bucket_name = std::env::var("BUCKET_NAME")
-        .expect("A BUCKET_NAME must be set in this app's Lambda environment variables.");
+    let config = Config::from_env();
 
     // Initialize the client here to be able to reuse it across
     // different invocations.
     //
     // No extra configuration is needed as long as your Lambda has
     // the necessary permissions attached to its role.
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let s3_client = aws_sdk_s3::Client::new(&config);
-
-    lambda_runtime::run(service_fn(|event: LambdaEvent<Request>| async {
-        put_object(&s3_client, &bucket_name, event).await
+    let aws_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let s3_client = aws_sdk_s3::Client::new(&aws_config);
+
+    // New indexes are built with the configured algorithm and settings; an
+    // existing one keeps whatever it was built with.
+    let index = match &config.index_location {
+        Some(location) => Some(
+            HashIndex::load(
+                &s3_client,
+                location.clone(),
+                config.index_algo,
+                config.index_config.clone(),
+            )
+            .await?,
+        ),
+        None => None,
+    };
+
+    // only images are read from the source; the index, write-backs and the
+    // result cache keep using `s3_client`.
+    let source: Box<dyn ImageSource> = match &config.source {
+        SourceConfig::S3 => Box::new(S3Source {
+            s3_client: s3_client.clone(),
+            retry: config.retry,
+        }),
+        SourceConfig::Local(root) => Box::new(LocalSource { root: root.clone() }),
+    };
+
+    let url_fetcher = UrlFetcher::new(config.url.clone());
+    let cache = ResultCache::new(&config.cache);
+
+    let state = State {
+        s3_client,
+        source,
+        url_fetcher,
+        config,
+        index,
+        cache,
+    };
+    lambda_runtime::run(service_fn(|event: LambdaEvent<Event>| async {
+        handle_event(&state, event).await
     }))
     .await
 }
@@ -0,0 +1,397 @@
+//! Metrics in CloudWatch's [Embedded Metric Format][emf], printed to stdout
+//! where Lambda picks them up from the logs.
+//!
+//! Metrics are plain tracing events with the [`TARGET`] target, so library
+//! code emits them without knowing where they end up. [`EmfLayer`] turns
+//! each one into a single EMF log line: string fields become dimensions and
+//! numeric fields become metrics, with units taken from their names.
+//!
+//! [emf]: https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+
+use crate::timings::Timings;
+use crate::HashResult;
+use image::ImageFormat;
+use image_hasher::HashAlg;
+use serde_json::{json, Map, Value};
+use std::fmt::Debug;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, Layer};
+
+/// The target of every metric event.
+pub const TARGET: &str = "metrics";
+
+/// Records that an image was hashed, counted once for each algorithm it was
+/// hashed with. The size and latencies of every stage are shared by all the
+/// algorithms, so they are only recorded for the primary one.
+pub fn image_hashed(hash: &HashResult, timings: &Timings) {
+    let (width, height) = hash.image_size;
+    let ms = |seconds: f64| seconds * 1000.0;
+    tracing::info!(
+        target: TARGET,
+        algorithm = ?hash.algo,
+        images_hashed = 1u64,
+        bytes = timings.bytes,
+        megapixels = f64::from(width) * f64::from(height) / 1e6,
+        request_ms = ms(timings.request),
+        download_ms = ms(timings.download),
+        format_detection_ms = ms(timings.format_detection),
+        decode_ms = ms(timings.decode),
+        hash_ms = ms(timings.hash),
+        total_ms = ms(timings.total),
+    );
+    for (algo, _) in hash.hashes.iter().filter(|(algo, _)| *algo != hash.algo) {
+        tracing::info!(target: TARGET, algorithm = ?algo, images_hashed = 1u64);
+    }
+}
+
+/// Records whether a result was found in the result cache, by the primary
+/// algorithm. Hits skip hashing, so they are not counted in `images_hashed`.
+pub fn cache_checked(algo: HashAlg, hit: bool) {
+    tracing::info!(
+        target: TARGET,
+        algorithm = ?algo,
+        cache_hits = u64::from(hit),
+        cache_misses = u64::from(!hit),
+    );
+}
+
+/// Records a comparison of two images with `algo`, and whether they were
+/// similar, taking `elapsed` in all.
+pub fn images_compared(algo: HashAlg, similar: bool, elapsed: Duration) {
+    tracing::info!(
+        target: TARGET,
+        algorithm = ?algo,
+        comparisons = 1u64,
+        similar_pairs = u64::from(similar),
+        total_ms = elapsed.as_secs_f64() * 1000.0,
+    );
+}
+
+/// Records that an image failed to decode, by the format its contents look
+/// like, or `unknown`.
+pub fn decode_failed(format: Option<ImageFormat>) {
+    let format = format.map_or("unknown", |format| format.extensions_str()[0]);
+    tracing::info!(target: TARGET, format, decode_failures = 1u64);
+}
+
+/// The namespace and fixed dimensions of every metric.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsConfig {
+    pub namespace: String,
+    /// Added to every metric on top of its own dimensions, in order.
+    pub dimensions: Vec<(String, String)>,
+}
+
+impl MetricsConfig {
+    /// Reads `METRICS_NAMESPACE` and `METRICS_DIMENSIONS`, the latter as
+    /// comma separated `name=value` pairs defaulting to the function name.
+    /// Metrics are off if the namespace is set to an empty string.
+    ///
+    /// # Panics
+    ///
+    /// If a dimension has no `=`.
+    pub fn from_env() -> Option<Self> {
+        let namespace =
+            std::env::var("METRICS_NAMESPACE").unwrap_or_else(|_| "LambdaImageHash".to_string());
+        if namespace.is_empty() {
+            return None;
+        }
+        let dimensions = match std::env::var("METRICS_DIMENSIONS") {
+            Ok(dimensions) => dimensions
+                .split(',')
+                .filter(|pair| !pair.trim().is_empty())
+                .map(|pair| {
+                    let (name, value) = pair
+                        .split_once('=')
+                        .expect("METRICS_DIMENSIONS must be comma separated `name=value` pairs");
+                    (name.trim().to_string(), value.trim().to_string())
+                })
+                .collect(),
+            Err(_) => std::env::var("AWS_LAMBDA_FUNCTION_NAME")
+                .map(|name| vec![("FunctionName".to_string(), name)])
+                .unwrap_or_default(),
+        };
+        Some(MetricsConfig {
+            namespace,
+            dimensions,
+        })
+    }
+}
+
+/// Prints metric events as EMF lines, ignoring every other event.
+pub struct EmfLayer<W = fn() -> std::io::Stdout> {
+    config: MetricsConfig,
+    make_writer: W,
+}
+
+impl EmfLayer {
+    pub fn new(config: MetricsConfig) -> Self {
+        EmfLayer {
+            config,
+            make_writer: std::io::stdout,
+        }
+    }
+}
+
+impl<W> EmfLayer<W> {
+    /// Prints to `make_writer` instead of stdout.
+    pub fn with_writer<W2>(self, make_writer: W2) -> EmfLayer<W2>
+    where
+        W2: for<'a> MakeWriter<'a> + 'static,
+    {
+        EmfLayer {
+            config: self.config,
+            make_writer,
+        }
+    }
+
+    fn to_emf(&self, dimensions: Vec<(String, String)>, metrics: Vec<(String, Value)>) -> Value {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut line = Map::new();
+        let mut names = Vec::new();
+        for (name, value) in self.config.dimensions.iter().cloned().chain(dimensions) {
+            names.push(name.clone());
+            line.insert(name, Value::String(value));
+        }
+        let definitions: Vec<Value> = metrics
+            .iter()
+            .map(|(name, value)| json!({ "Name": name, "Unit": unit(name, value) }))
+            .collect();
+        line.extend(metrics);
+        line.insert(
+            "_aws".to_string(),
+            json!({
+                "Timestamp": timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": self.config.namespace,
+                    "Dimensions": [names],
+                    "Metrics": definitions,
+                }],
+            }),
+        );
+        Value::Object(line)
+    }
+}
+
+/// The unit of a metric, from its name: `_ms` for milliseconds and `bytes`
+/// for bytes, and otherwise a count for integers.
+fn unit(name: &str, value: &Value) -> &'static str {
+    if name.ends_with("_ms") {
+        "Milliseconds"
+    } else if name.ends_with("bytes") {
+        "Bytes"
+    } else if value.is_u64() || value.is_i64() {
+        "Count"
+    } else {
+        "None"
+    }
+}
+
+impl<S, W> Layer<S> for EmfLayer<W>
+where
+    S: Subscriber,
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target() != TARGET {
+            return;
+        }
+        let mut visitor = MetricVisitor::default();
+        event.record(&mut visitor);
+        let line = self.to_emf(visitor.dimensions, visitor.metrics);
+        // a metric that can't be written is not worth failing the invocation.
+        let _ = writeln!(self.make_writer.make_writer(), "{line}");
+    }
+}
+
+#[derive(Default)]
+struct MetricVisitor {
+    dimensions: Vec<(String, String)>,
+    metrics: Vec<(String, Value)>,
+}
+
+impl Visit for MetricVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.metrics.push((field.name().to_string(), json!(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.metrics.push((field.name().to_string(), json!(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.metrics.push((field.name().to_string(), json!(value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.dimensions
+            .push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() != "message" {
+            self.dimensions
+                .push((field.name().to_string(), format!("{value:?}")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::HashConfig;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::prelude::*;
+
+    /// Collects everything written to it, shared with the test.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn lines(&self) -> Vec<Value> {
+            let output = self.0.lock().unwrap();
+            String::from_utf8_lossy(&output)
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    fn emit(emit: impl FnOnce()) -> Vec<Value> {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let layer = EmfLayer::new(MetricsConfig {
+            namespace: "Test".to_string(),
+            dimensions: vec![("FunctionName".to_string(), "hasher".to_string())],
+        })
+        .with_writer(move || writer.clone());
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), emit);
+        captured.lines()
+    }
+
+    /// The unit of every metric a line defines, by name.
+    fn units(line: &Value) -> Vec<(String, String)> {
+        line["_aws"]["CloudWatchMetrics"][0]["Metrics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| {
+                let name = m["Name"].as_str().unwrap().to_string();
+                (name, m["Unit"].as_str().unwrap().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hashed_images_are_counted_for_each_algorithm() {
+        let hash = HashResult {
+            hash_base64: "AAAAAAAAAAA".to_string(),
+            algo: HashAlg::Gradient,
+            hashes: vec![
+                (HashAlg::Gradient, "AAAAAAAAAAA".to_string()),
+                (HashAlg::Mean, "AAAAAAAAAAA".to_string()),
+            ],
+            config: HashConfig::default(),
+            invariance: None,
+            transforms: None,
+            image_size: (2000, 1000),
+            time_elapsed: 0.5,
+        };
+        let timings = Timings {
+            bytes: 4096,
+            hash: 0.5,
+            ..Timings::default()
+        };
+        let lines = emit(|| {
+            image_hashed(&hash, &timings);
+            tracing::info!(not_a_metric = 1u64, "ignored");
+        });
+        assert_eq!(lines.len(), 2);
+
+        let primary = &lines[0];
+        let metrics = &primary["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(metrics["Namespace"], "Test");
+        assert_eq!(
+            metrics["Dimensions"],
+            json!([["FunctionName", "algorithm"]])
+        );
+        assert!(primary["_aws"]["Timestamp"].is_u64());
+        assert_eq!(primary["FunctionName"], "hasher");
+        assert_eq!(primary["algorithm"], "Gradient");
+        assert_eq!(primary["images_hashed"], 1);
+        assert_eq!(primary["bytes"], 4096);
+        assert_eq!(primary["megapixels"], 2.0);
+        assert_eq!(primary["hash_ms"], 500.0);
+        let primary_units = units(primary);
+        let unit = |name: &str| {
+            let (_, unit) = primary_units.iter().find(|(n, _)| n == name).unwrap();
+            unit.clone()
+        };
+        assert_eq!(unit("images_hashed"), "Count");
+        assert_eq!(unit("bytes"), "Bytes");
+        assert_eq!(unit("megapixels"), "None");
+        assert_eq!(unit("total_ms"), "Milliseconds");
+
+        let other = &lines[1];
+        assert_eq!(other["algorithm"], "Mean");
+        assert_eq!(other["images_hashed"], 1);
+        assert_eq!(
+            units(other),
+            [("images_hashed".to_string(), "Count".to_string())]
+        );
+    }
+
+    #[test]
+    fn decode_failures_are_counted_by_format() {
+        let lines = emit(|| {
+            decode_failed(Some(ImageFormat::WebP));
+            decode_failed(None);
+        });
+        assert_eq!(lines[0]["format"], "webp");
+        assert_eq!(lines[1]["format"], "unknown");
+        assert_eq!(lines[1]["decode_failures"], 1);
+        assert_eq!(
+            lines[1]["_aws"]["CloudWatchMetrics"][0]["Dimensions"],
+            json!([["FunctionName", "format"]])
+        );
+    }
+
+    #[test]
+    fn cache_lookups_and_comparisons_are_counted() {
+        let lines = emit(|| {
+            cache_checked(HashAlg::Mean, true);
+            cache_checked(HashAlg::Gradient, false);
+            images_compared(HashAlg::Gradient, true, Duration::from_millis(250));
+        });
+        assert_eq!(lines[0]["algorithm"], "Mean");
+        assert_eq!(
+            (&lines[0]["cache_hits"], &lines[0]["cache_misses"]),
+            (&json!(1), &json!(0))
+        );
+        assert_eq!(
+            (&lines[1]["cache_hits"], &lines[1]["cache_misses"]),
+            (&json!(0), &json!(1))
+        );
+        assert_eq!(lines[2]["comparisons"], 1);
+        assert_eq!(lines[2]["similar_pairs"], 1);
+        assert_eq!(lines[2]["total_ms"], 250.0);
+        assert!(units(&lines[2]).contains(&("total_ms".to_string(), "Milliseconds".to_string())));
+    }
+}
@@ -0,0 +1,298 @@
+//! Tuning of the perceptual hash beyond the choice of algorithm.
+
+use crate::TypedError;
+use image::imageops::FilterType;
+use image_hasher::{HashAlg, HasherConfig};
+use serde::{Deserialize, Serialize};
+
+/// Largest hash width or height accepted in a request.
+const MAX_HASH_DIMENSION: u32 = 64;
+
+/// Optional hasher settings as given in a request.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HashOptions {
+    pub hash_width: Option<u32>,
+    pub hash_height: Option<u32>,
+    pub resize_filter: Option<ResizeFilter>,
+    #[serde(default)]
+    pub preproc_dct: bool,
+    pub preproc_diff_gauss: Option<DiffGauss>,
+}
+
+/// The filter used to scale the image down before hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Difference of Gaussians preprocessing, either `true` for the default
+/// sigmas or an explicit `[sigma_a, sigma_b]` pair.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum DiffGauss {
+    Enabled(bool),
+    Sigmas([f32; 2]),
+}
+
+/// The hasher settings actually used, echoed back in the response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HashConfig {
+    pub hash_width: u32,
+    pub hash_height: u32,
+    pub resize_filter: ResizeFilter,
+    pub preproc_dct: bool,
+    /// The `[sigma_a, sigma_b]` pair, when Difference of Gaussians is on.
+    pub preproc_diff_gauss: Option<[f32; 2]>,
+}
+
+impl Default for HashConfig {
+    /// The same defaults as [`HasherConfig::new`].
+    fn default() -> Self {
+        HashConfig {
+            hash_width: 8,
+            hash_height: 8,
+            resize_filter: ResizeFilter::Lanczos3,
+            preproc_dct: false,
+            preproc_diff_gauss: None,
+        }
+    }
+}
+
+impl HashOptions {
+    /// Fills in the defaults and rejects settings that `algos` can't honour,
+    /// rather than letting image_hasher silently round or ignore them.
+    pub fn resolve(&self, algos: &[HashAlg]) -> Result<HashConfig, TypedError> {
+        let invalid = |msg: String| Err(TypedError::InvalidRequest(msg));
+        let defaults = HashConfig::default();
+        let hash_width = self.hash_width.unwrap_or(defaults.hash_width);
+        let hash_height = self.hash_height.unwrap_or(defaults.hash_height);
+
+        for (name, value) in [("hash_width", hash_width), ("hash_height", hash_height)] {
+            if value == 0 || value > MAX_HASH_DIMENSION {
+                return invalid(format!(
+                    "`{name}` must be between 1 and {MAX_HASH_DIMENSION}, got {value}"
+                ));
+            }
+        }
+        for &algo in algos {
+            let multiple = match algo {
+                HashAlg::DoubleGradient => 2,
+                HashAlg::Blockhash => 4,
+                _ => 1,
+            };
+            if !hash_width.is_multiple_of(multiple) || !hash_height.is_multiple_of(multiple) {
+                return invalid(format!(
+                    "{algo:?} needs a hash size in multiples of {multiple}, got {hash_width}x{hash_height}"
+                ));
+            }
+        }
+
+        let blockhash = algos.contains(&HashAlg::Blockhash);
+        if blockhash && self.resize_filter.is_some() {
+            return invalid("`resize_filter` has no effect with Blockhash".to_string());
+        }
+        if blockhash && self.preproc_dct {
+            return invalid("`preproc_dct` has no effect with Blockhash".to_string());
+        }
+
+        let preproc_diff_gauss = match self.preproc_diff_gauss {
+            None | Some(DiffGauss::Enabled(false)) => None,
+            Some(DiffGauss::Enabled(true)) => Some([5.0, 10.0]),
+            Some(DiffGauss::Sigmas(sigmas)) => {
+                if sigmas.iter().any(|s| !s.is_finite() || *s <= 0.0) {
+                    return invalid(format!(
+                        "`preproc_diff_gauss` sigmas must be positive, got {sigmas:?}"
+                    ));
+                }
+                Some(sigmas)
+            }
+        };
+
+        Ok(HashConfig {
+            hash_width,
+            hash_height,
+            resize_filter: self.resize_filter.unwrap_or(defaults.resize_filter),
+            preproc_dct: self.preproc_dct,
+            preproc_diff_gauss,
+        })
+    }
+}
+
+impl HashConfig {
+    /// How many bits a hash of `algo` has with these settings, which the
+    /// last byte of a hash may have fewer of than 8.
+    ///
+    /// `DoubleGradient` compares neighbours across and down an image scaled
+    /// to half the hash size plus one, so it has fewer bits than the others.
+    pub fn hash_bits(&self, algo: HashAlg) -> u32 {
+        let (width, height) = (self.hash_width, self.hash_height);
+        match algo {
+            HashAlg::DoubleGradient => {
+                let (half_width, half_height) = (width.div_ceil(2), height.div_ceil(2));
+                half_width * (half_height + 1) + (half_width + 1) * half_height
+            }
+            _ => width * height,
+        }
+    }
+
+    /// Builds the image_hasher configuration for one algorithm.
+    pub fn hasher_config(&self, algo: HashAlg) -> HasherConfig {
+        let mut config = HasherConfig::new()
+            .hash_alg(algo)
+            .hash_size(self.hash_width, self.hash_height)
+            .resize_filter(self.resize_filter.into());
+        if self.preproc_dct {
+            config = config.preproc_dct();
+        }
+        if let Some([sigma_a, sigma_b]) = self.preproc_diff_gauss {
+            config = config.preproc_diff_gauss_sigmas(sigma_a, sigma_b);
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbImage};
+
+    fn options(value: serde_json::Value) -> HashOptions {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn error(options: HashOptions, algos: &[HashAlg]) -> String {
+        options.resolve(algos).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn options_default_to_the_hasher_defaults() {
+        let config = HashOptions::default()
+            .resolve(&[HashAlg::Gradient])
+            .unwrap();
+        assert_eq!(config, HashConfig::default());
+        let config = options(serde_json::json!({"preproc_diff_gauss": true}))
+            .resolve(&[HashAlg::Gradient])
+            .unwrap();
+        assert_eq!(config.preproc_diff_gauss, Some([5.0, 10.0]));
+        let config = options(serde_json::json!({"preproc_diff_gauss": false}))
+            .resolve(&[HashAlg::Gradient])
+            .unwrap();
+        assert_eq!(config.preproc_diff_gauss, None);
+    }
+
+    #[test]
+    fn hash_sizes_are_bounded() {
+        let size = |width, height| {
+            options(serde_json::json!({
+                "hash_width": width,
+                "hash_height": height,
+            }))
+        };
+        assert!(size(1, 64).resolve(&[HashAlg::Mean]).is_ok());
+        assert_eq!(
+            error(size(0, 8), &[HashAlg::Mean]),
+            "Invalid request: `hash_width` must be between 1 and 64, got 0"
+        );
+        assert_eq!(
+            error(size(8, 65), &[HashAlg::Mean]),
+            "Invalid request: `hash_height` must be between 1 and 64, got 65"
+        );
+        // every algorithm is checked, not just the primary one.
+        assert_eq!(
+            error(size(6, 6), &[HashAlg::Mean, HashAlg::Blockhash]),
+            "Invalid request: Blockhash needs a hash size in multiples of 4, got 6x6"
+        );
+    }
+
+    #[test]
+    fn filters_are_parsed_by_name() {
+        let config = options(serde_json::json!({"resize_filter": "CatmullRom"}))
+            .resolve(&[HashAlg::Gradient])
+            .unwrap();
+        assert_eq!(config.resize_filter, ResizeFilter::CatmullRom);
+        let unknown = serde_json::from_value::<HashOptions>(serde_json::json!({
+            "resize_filter": "Bicubic",
+        }));
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn conflicting_preprocessing_is_refused() {
+        let blockhash = [HashAlg::Gradient, HashAlg::Blockhash];
+        assert_eq!(
+            error(
+                options(serde_json::json!({"preproc_dct": true})),
+                &blockhash
+            ),
+            "Invalid request: `preproc_dct` has no effect with Blockhash"
+        );
+        assert_eq!(
+            error(
+                options(serde_json::json!({"resize_filter": "Nearest"})),
+                &blockhash
+            ),
+            "Invalid request: `resize_filter` has no effect with Blockhash"
+        );
+        assert_eq!(
+            error(
+                options(serde_json::json!({"preproc_diff_gauss": [1.0, -2.0]})),
+                &[HashAlg::Gradient]
+            ),
+            "Invalid request: `preproc_diff_gauss` sigmas must be positive, got [1.0, -2.0]"
+        );
+    }
+
+    #[test]
+    fn hash_bits_count_the_bits_of_real_hashes() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(40, 30, |x, y| {
+            image::Rgb([(x * 6) as u8, (y * 8) as u8, ((x + y) * 3) as u8])
+        }));
+        let algos = [
+            HashAlg::Mean,
+            HashAlg::Median,
+            HashAlg::Gradient,
+            HashAlg::VertGradient,
+            HashAlg::DoubleGradient,
+            HashAlg::Blockhash,
+        ];
+        for (hash_width, hash_height) in [(8, 8), (5, 5), (16, 4), (2, 2)] {
+            let options = HashOptions {
+                hash_width: Some(hash_width),
+                hash_height: Some(hash_height),
+                ..HashOptions::default()
+            };
+            // sizes an algorithm can't take are refused before hashing.
+            for algo in algos {
+                let Ok(config) = options.resolve(&[algo]) else {
+                    continue;
+                };
+                let hash = config.hasher_config(algo).to_hasher().hash_image(&img);
+                let bits = config.hash_bits(algo);
+                assert_eq!(
+                    hash.as_bytes().len(),
+                    bits.div_ceil(8) as usize,
+                    "{algo:?} {hash_width}x{hash_height}"
+                );
+            }
+        }
+        let config = HashConfig::default();
+        assert_eq!(config.hash_bits(HashAlg::Gradient), 64);
+        assert_eq!(config.hash_bits(HashAlg::DoubleGradient), 40);
+    }
+}
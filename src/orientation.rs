@@ -0,0 +1,351 @@
+//! Turning photos upright as their EXIF orientation says, so that a photo
+//! stored sideways hashes the same as a copy with the rotation baked in.
+
+use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+/// The EXIF `Orientation` tag.
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// An EXIF orientation from 1 to 8, telling how the stored pixels must be
+/// rotated and flipped to be upright. 1 means they already are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Orientation(u16);
+
+impl Orientation {
+    /// The orientation for an EXIF tag value, if it is a valid one.
+    pub fn from_exif(value: u16) -> Option<Self> {
+        (1..=8).contains(&value).then_some(Orientation(value))
+    }
+
+    pub fn to_exif(self) -> u16 {
+        self.0
+    }
+
+    /// Rotates and flips `img` upright.
+    pub fn apply(self, img: DynamicImage) -> DynamicImage {
+        match self.0 {
+            2 => img.fliph(),
+            3 => img.rotate180(),
+            4 => img.flipv(),
+            5 => img.rotate90().fliph(),
+            6 => img.rotate90(),
+            7 => img.rotate270().fliph(),
+            8 => img.rotate270(),
+            _ => img,
+        }
+    }
+}
+
+/// Reads the EXIF orientation of an encoded JPEG, PNG, WebP or TIFF image.
+/// `None` if it has no EXIF metadata, no orientation, or isn't readable.
+pub fn read_orientation(bytes: &[u8]) -> Option<Orientation> {
+    let tiff = match image::guess_format(bytes).ok()? {
+        ImageFormat::Jpeg => jpeg_exif(bytes)?,
+        ImageFormat::Png => png_exif(bytes)?,
+        ImageFormat::WebP => webp_exif(bytes)?,
+        ImageFormat::Tiff => bytes,
+        _ => return None,
+    };
+    Orientation::from_exif(tiff_orientation(tiff)?)
+}
+
+/// The TIFF structure in the `Exif` APP1 segment, which comes before the
+/// image data.
+fn jpeg_exif(bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 2;
+    loop {
+        if *bytes.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *bytes.get(pos + 1)?;
+        match marker {
+            // padding before a marker
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // start of scan or end of image
+            0xDA | 0xD9 => return None,
+            _ => {}
+        }
+        let length = usize::from(u16::from_be_bytes([
+            *bytes.get(pos + 2)?,
+            *bytes.get(pos + 3)?,
+        ]));
+        // the length counts its own two bytes
+        if length < 2 {
+            return None;
+        }
+        let segment = bytes.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xE1 {
+            if let Some(tiff) = segment.strip_prefix(b"Exif\0\0") {
+                return Some(tiff);
+            }
+        }
+        pos += 2 + length;
+    }
+}
+
+/// The contents of the `eXIf` chunk.
+fn png_exif(bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 8;
+    loop {
+        let length = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let kind = bytes.get(pos + 4..pos + 8)?;
+        let data = bytes.get(pos + 8..(pos + 8).checked_add(length)?)?;
+        match kind {
+            b"eXIf" => return Some(data),
+            b"IEND" => return None,
+            _ => pos += 12 + length,
+        }
+    }
+}
+
+/// The contents of the `EXIF` chunk, which some writers start with the same
+/// `Exif` header as JPEG.
+fn webp_exif(bytes: &[u8]) -> Option<&[u8]> {
+    let mut pos = 12;
+    loop {
+        let kind = bytes.get(pos..pos + 4)?;
+        let length = u32::from_le_bytes(bytes.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        let data = bytes.get(pos + 8..(pos + 8).checked_add(length)?)?;
+        if kind == b"EXIF" {
+            return Some(data.strip_prefix(b"Exif\0\0").unwrap_or(data));
+        }
+        // chunks are padded to an even length
+        pos += 8 + length + (length & 1);
+    }
+}
+
+/// The orientation tag of the first IFD of a TIFF structure.
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| {
+        let raw = tiff.get(pos..pos.checked_add(2)?)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(raw)
+        } else {
+            u16::from_le_bytes(raw)
+        })
+    };
+    let u32_at = |pos: usize| {
+        let raw = tiff.get(pos..pos.checked_add(4)?)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(raw)
+        } else {
+            u32::from_le_bytes(raw)
+        })
+    };
+    if u16_at(2)? != 42 {
+        return None;
+    }
+    let ifd = usize::try_from(u32_at(4)?).ok()?;
+    let entries = usize::from(u16_at(ifd)?);
+    (0..entries)
+        .map_while(|i| ifd.checked_add(2 + i * 12))
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+        // a SHORT value sits in the first two bytes of the value field
+        .and_then(|entry| u16_at(entry + 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    /// A TIFF structure whose first IFD holds an unrelated tag and then the
+    /// orientation, in either byte order.
+    fn tiff(big_endian: bool, orientation: u16) -> Vec<u8> {
+        let u16_bytes = |v: u16| match big_endian {
+            true => v.to_be_bytes(),
+            false => v.to_le_bytes(),
+        };
+        let u32_bytes = |v: u32| match big_endian {
+            true => v.to_be_bytes(),
+            false => v.to_le_bytes(),
+        };
+        let mut tiff = Vec::new();
+        tiff.extend(if big_endian { b"MM" } else { b"II" });
+        tiff.extend(u16_bytes(42));
+        tiff.extend(u32_bytes(8));
+        tiff.extend(u16_bytes(2));
+        // ImageWidth, LONG, 1, 640
+        tiff.extend(u16_bytes(0x0100));
+        tiff.extend(u16_bytes(4));
+        tiff.extend(u32_bytes(1));
+        tiff.extend(u32_bytes(640));
+        // Orientation, SHORT, 1, value padded to four bytes
+        tiff.extend(u16_bytes(ORIENTATION_TAG));
+        tiff.extend(u16_bytes(3));
+        tiff.extend(u32_bytes(1));
+        tiff.extend(u16_bytes(orientation));
+        tiff.extend([0, 0]);
+        tiff.extend(u32_bytes(0));
+        tiff
+    }
+
+    fn jpeg(tiff: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        // a JFIF segment before the EXIF one
+        jpeg.extend([0xFF, 0xE0, 0x00, 0x07]);
+        jpeg.extend(b"JFIF\0");
+        let exif = [b"Exif\0\0".as_slice(), tiff].concat();
+        jpeg.extend([0xFF, 0xE1]);
+        jpeg.extend((exif.len() as u16 + 2).to_be_bytes());
+        jpeg.extend(exif);
+        jpeg.extend([0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xD9]);
+        jpeg
+    }
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend(kind);
+        chunk.extend(data);
+        // the CRC isn't checked
+        chunk.extend([0; 4]);
+        chunk
+    }
+
+    fn png(tiff: &[u8]) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(png_chunk(b"IHDR", &[0; 13]));
+        png.extend(png_chunk(b"eXIf", tiff));
+        png.extend(png_chunk(b"IEND", &[]));
+        png
+    }
+
+    fn webp(tiff: &[u8], exif_header: bool) -> Vec<u8> {
+        let mut chunks = b"VP8X".to_vec();
+        chunks.extend(10u32.to_le_bytes());
+        chunks.extend([0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let exif = match exif_header {
+            true => [b"Exif\0\0".as_slice(), tiff].concat(),
+            false => tiff.to_vec(),
+        };
+        chunks.extend(b"EXIF");
+        chunks.extend((exif.len() as u32).to_le_bytes());
+        chunks.extend(&exif);
+        if exif.len() % 2 == 1 {
+            chunks.push(0);
+        }
+        let mut webp = b"RIFF".to_vec();
+        webp.extend((chunks.len() as u32 + 4).to_le_bytes());
+        webp.extend(b"WEBP");
+        webp.extend(chunks);
+        webp
+    }
+
+    #[test]
+    fn orientations_are_read_from_every_container() {
+        for big_endian in [false, true] {
+            for value in 1..=8 {
+                let tiff = tiff(big_endian, value);
+                let expected = Orientation::from_exif(value);
+                assert!(expected.is_some());
+                assert_eq!(read_orientation(&jpeg(&tiff)), expected);
+                assert_eq!(read_orientation(&png(&tiff)), expected);
+                assert_eq!(read_orientation(&webp(&tiff, true)), expected);
+                assert_eq!(read_orientation(&webp(&tiff, false)), expected);
+                assert_eq!(read_orientation(&tiff), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_orientations_are_ignored() {
+        for value in [0, 9, 0xFFFF] {
+            assert_eq!(read_orientation(&jpeg(&tiff(false, value))), None);
+        }
+        let mut bad_magic = tiff(false, 6);
+        bad_magic[2] = 43;
+        assert_eq!(read_orientation(&jpeg(&bad_magic)), None);
+    }
+
+    #[test]
+    fn truncated_images_have_no_orientation() {
+        let tiff = tiff(true, 6);
+        for image in [jpeg(&tiff), png(&tiff), webp(&tiff, true)] {
+            for end in 0..image.len() {
+                // whatever is left must not panic, loop or read past the end
+                let _ = read_orientation(&image[..end]);
+            }
+        }
+        // cut inside the IFD entry holding the orientation
+        let cut = &tiff[..tiff.len() - 10];
+        assert_eq!(read_orientation(&jpeg(cut)), None);
+    }
+
+    #[test]
+    fn corrupt_lengths_are_refused() {
+        for length in [0u8, 1] {
+            let jpeg = [0xFF, 0xD8, 0xFF, 0xE1, 0x00, length, 0xFF, 0xD9];
+            assert_eq!(jpeg_exif(&jpeg), None);
+        }
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(u32::MAX.to_be_bytes());
+        png.extend(b"tEXt");
+        png.extend([0; 16]);
+        assert_eq!(png_exif(&png), None);
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        webp.extend(b"EXIF");
+        webp.extend(u32::MAX.to_le_bytes());
+        assert_eq!(webp_exif(&webp), None);
+
+        let mut far_ifd = tiff(false, 6);
+        far_ifd[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(tiff_orientation(&far_ifd), None);
+        let mut many_entries = tiff(false, 6);
+        many_entries[8..10].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(tiff_orientation(&many_entries), Some(6));
+    }
+
+    /// Where the stored pixel at `(x, y)` of a `width` by `height` image is
+    /// shown, as the EXIF specification describes each orientation.
+    fn shown_at(orientation: u16, (x, y): (u32, u32), (width, height): (u32, u32)) -> (u32, u32) {
+        let (right, bottom) = (width - 1, height - 1);
+        match orientation {
+            1 => (x, y),
+            2 => (right - x, y),
+            3 => (right - x, bottom - y),
+            4 => (x, bottom - y),
+            // the first row is shown as the left column, the first column
+            // as the top row
+            5 => (y, x),
+            6 => (bottom - y, x),
+            // the first row is shown as the right column, the first column
+            // as the bottom row
+            7 => (bottom - y, right - x),
+            8 => (y, right - x),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn images_are_turned_upright() {
+        let size = (3, 2);
+        let stored = RgbaImage::from_fn(size.0, size.1, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        for value in 1..=8 {
+            let orientation = Orientation::from_exif(value).unwrap();
+            let upright = orientation.apply(DynamicImage::ImageRgba8(stored.clone()));
+            let expected_size = match value {
+                5..=8 => (size.1, size.0),
+                _ => size,
+            };
+            assert_eq!(upright.dimensions(), expected_size, "orientation {value}");
+            for (x, y, pixel) in stored.enumerate_pixels() {
+                let (shown_x, shown_y) = shown_at(value, (x, y), size);
+                assert_eq!(
+                    upright.get_pixel(shown_x, shown_y),
+                    *pixel,
+                    "orientation {value}, pixel ({x}, {y})"
+                );
+            }
+        }
+    }
+}
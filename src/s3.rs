@@ -0,0 +1,550 @@
+//! Fetching objects from S3.
+
+use crate::error::BoxError;
+use crate::timings::Timings;
+use crate::TypedError;
+use aws_sdk_s3::config::retry::RetryConfig;
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::primitives::{ByteStream, DateTime};
+use bytes::{Bytes, BytesMut};
+use futures::FutureExt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// A resolved bucket and key pair.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct S3Location {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl std::fmt::Display for S3Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+impl S3Location {
+    /// Resolves where `path` lives, preferring the bucket from an `s3://` URI,
+    /// then the explicit `bucket`, and finally `default_bucket`.
+    pub fn resolve(
+        path: &str,
+        bucket: Option<&str>,
+        default_bucket: Option<&str>,
+    ) -> Result<Self, TypedError> {
+        if let Some(rest) = path.strip_prefix("s3://") {
+            let (uri_bucket, key) = rest
+                .split_once('/')
+                .filter(|(b, k)| !b.is_empty() && !k.is_empty())
+                .ok_or_else(|| TypedError::InvalidPath(path.to_string()))?;
+            if bucket.is_some_and(|b| b != uri_bucket) {
+                return Err(TypedError::InvalidPath(path.to_string()));
+            }
+            return Ok(S3Location {
+                bucket: uri_bucket.to_string(),
+                key: key.to_string(),
+            });
+        }
+        if path.is_empty() {
+            return Err(TypedError::InvalidPath(path.to_string()));
+        }
+        let bucket = bucket
+            .or(default_bucket)
+            .ok_or_else(|| TypedError::MissingBucket(path.to_string()))?;
+        Ok(S3Location {
+            bucket: bucket.to_string(),
+            key: path.to_string(),
+        })
+    }
+}
+
+/// Which version of an object an image was decoded from.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectVersion {
+    pub e_tag: Option<String>,
+    pub version_id: Option<String>,
+    pub last_modified: Option<DateTime>,
+}
+
+/// The raw contents of an object and the version they were read from.
+pub struct FetchedObject {
+    pub bytes: Bytes,
+    pub version: ObjectVersion,
+    /// How many attempts it took to read the object.
+    pub attempts: u32,
+    /// The time spent requesting and downloading the object, and its size.
+    pub timings: Timings,
+}
+
+/// How failed S3 downloads are retried. The SDK's own retries are disabled
+/// for them, so this is the only policy that applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    /// Longest delay before the first retry, doubled for each later one.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A random delay up to the exponential backoff for retrying after
+    /// `attempt` failed, so that invocations throttled together don't all
+    /// retry together.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        ceiling.mul_f64(fastrand::f64())
+    }
+}
+
+pub async fn download_from_s3(
+    s3_client: &aws_sdk_s3::Client,
+    bucket_name: &str,
+    key: &str,
+) -> Result<GetObjectOutput, TypedError> {
+    let response = s3_client
+        .get_object()
+        .bucket(bucket_name)
+        .key(key)
+        .customize()
+        .config_override(
+            aws_sdk_s3::config::Builder::default().retry_config(RetryConfig::disabled()),
+        )
+        .send()
+        .await;
+    match response {
+        Ok(output) => {
+            tracing::info!(
+                bucket = %bucket_name,
+                key = %key,
+                "data successfully retrieved from S3",
+            );
+            Ok(output)
+        }
+        Err(err) => {
+            tracing::error!(
+                err = %err,
+                bucket = %bucket_name,
+                key = %key,
+                "failed to retrieve data from S3"
+            );
+            let location = S3Location {
+                bucket: bucket_name.to_string(),
+                key: key.to_string(),
+            };
+            Err(s3_error(&location, err, TypedError::S3Get))
+        }
+    }
+}
+
+/// The current version of an object, read without downloading it.
+pub async fn head_object(
+    s3_client: &aws_sdk_s3::Client,
+    location: &S3Location,
+) -> Result<ObjectVersion, TypedError> {
+    let output = s3_client
+        .head_object()
+        .bucket(&location.bucket)
+        .key(&location.key)
+        .send()
+        .await
+        .map_err(|err| {
+            tracing::error!(
+                err = %err,
+                bucket = %location.bucket,
+                key = %location.key,
+                "failed to read object metadata from S3"
+            );
+            s3_error(location, err, TypedError::S3Get)
+        })?;
+    Ok(ObjectVersion {
+        e_tag: output.e_tag,
+        version_id: output.version_id,
+        last_modified: output.last_modified,
+    })
+}
+
+/// Downloads the whole body of an object, unless S3 reports it to be larger
+/// than `max_bytes`.
+///
+/// Retryable failures of the request or the body download are retried as
+/// `retry` says, as long as the retry can start before `deadline`. Every
+/// attempt, the first one included, is cut short at `deadline` too.
+pub async fn fetch_object(
+    s3_client: &aws_sdk_s3::Client,
+    location: &S3Location,
+    max_bytes: u64,
+    retry: &RetryPolicy,
+    deadline: Option<Instant>,
+) -> Result<FetchedObject, TypedError> {
+    with_retries(location, retry, deadline, || async {
+        let mut timings = Timings::default();
+        let result = fetch_object_once(s3_client, location, max_bytes, &mut timings).await;
+        (result, timings)
+    })
+    .await
+}
+
+/// Runs `fetch` until it succeeds or fails for good, summing the timings of
+/// every attempt.
+async fn with_retries<F: Future<Output = (Result<FetchedObject, TypedError>, Timings)>>(
+    location: &S3Location,
+    retry: &RetryPolicy,
+    deadline: Option<Instant>,
+    mut fetch: impl FnMut() -> F,
+) -> Result<FetchedObject, TypedError> {
+    let mut attempt = 1;
+    let mut timings = Timings::default();
+    loop {
+        let fetch = fetch().map(|(result, attempt_timings)| {
+            timings.request += attempt_timings.request;
+            timings.download += attempt_timings.download;
+            result
+        });
+        let result = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), fetch)
+                .await
+                .unwrap_or_else(|elapsed| {
+                    Err(TypedError::S3Timeout(
+                        location.to_string(),
+                        Box::new(elapsed),
+                    ))
+                }),
+            None => fetch.await,
+        };
+        let err = match result {
+            Ok(mut object) => {
+                object.attempts = attempt;
+                object.timings = Timings {
+                    bytes: object.bytes.len() as u64,
+                    ..timings
+                };
+                tracing::info!(
+                    bucket = %location.bucket,
+                    key = %location.key,
+                    attempts = attempt,
+                    bytes = object.bytes.len(),
+                    "object downloaded from S3",
+                );
+                return Ok(object);
+            }
+            Err(err) => err,
+        };
+
+        let delay = retry.backoff(attempt);
+        let in_time = deadline.is_none_or(|deadline| Instant::now() + delay < deadline);
+        if !err.is_retryable() || attempt >= retry.max_attempts || !in_time {
+            tracing::error!(
+                err = %err,
+                bucket = %location.bucket,
+                key = %location.key,
+                attempts = attempt,
+                "giving up downloading from S3"
+            );
+            return Err(err);
+        }
+        tracing::warn!(
+            err = %err,
+            bucket = %location.bucket,
+            key = %location.key,
+            attempt,
+            delay_ms = delay.as_millis() as u64,
+            "retrying S3 download"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+async fn fetch_object_once(
+    s3_client: &aws_sdk_s3::Client,
+    location: &S3Location,
+    max_bytes: u64,
+    timings: &mut Timings,
+) -> Result<FetchedObject, TypedError> {
+    let start = Instant::now();
+    let response = download_from_s3(s3_client, &location.bucket, &location.key).await;
+    Timings::add(&mut timings.request, start);
+    let response = response?;
+    // dropping the response unread closes the connection without downloading.
+    let length = response.content_length.map(|length| length.max(0) as u64);
+    if let Some(length) = length.filter(|&length| length > max_bytes) {
+        return Err(TypedError::ObjectTooLarge(
+            location.to_string(),
+            length,
+            max_bytes,
+        ));
+    }
+    let version = ObjectVersion {
+        e_tag: response.e_tag.clone(),
+        version_id: response.version_id.clone(),
+        last_modified: response.last_modified,
+    };
+
+    let start = Instant::now();
+    let data = read_body(response.body, location, length, max_bytes).await;
+    Timings::add(&mut timings.download, start);
+
+    Ok(FetchedObject {
+        bytes: data?,
+        version,
+        attempts: 1,
+        timings: Timings::default(),
+    })
+}
+
+/// Reads `body` a chunk at a time, failing as soon as more than `max_bytes`
+/// came in, as S3 may leave out the length or the body may not match it.
+async fn read_body(
+    mut body: ByteStream,
+    location: &S3Location,
+    length: Option<u64>,
+    max_bytes: u64,
+) -> Result<Bytes, TypedError> {
+    let capacity = length.unwrap_or_default().min(max_bytes);
+    let mut data = BytesMut::with_capacity(capacity as usize);
+    while let Some(chunk) = body
+        .try_next()
+        .await
+        .map_err(|e| TypedError::S3Download(location.to_string(), Box::new(e)))?
+    {
+        let read = (data.len() + chunk.len()) as u64;
+        if read > max_bytes {
+            return Err(TypedError::ObjectTooLarge(
+                location.to_string(),
+                read,
+                max_bytes,
+            ));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data.freeze())
+}
+
+/// Tells apart the S3 failures callers handle differently, falling back to
+/// `other` for the rest. The SDK error is kept as the source either way.
+pub fn s3_error<E>(
+    location: &S3Location,
+    err: SdkError<E>,
+    other: fn(String, BoxError) -> TypedError,
+) -> TypedError
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    let key = location.to_string();
+    let status = err.raw_response().map(|r| r.status().as_u16());
+    let timed_out = match &err {
+        SdkError::TimeoutError(_) => true,
+        SdkError::DispatchFailure(failure) => failure.is_timeout(),
+        _ => false,
+    };
+    let unavailable = matches!(&err, SdkError::DispatchFailure(failure) if failure.is_io());
+    match (err.code(), status) {
+        (Some("NoSuchKey" | "NoSuchBucket" | "NotFound"), _) | (_, Some(404)) => {
+            TypedError::NotFound(key, Some(Box::new(err)))
+        }
+        (Some("AccessDenied"), _) | (_, Some(403)) => {
+            TypedError::S3AccessDenied(key, Box::new(err))
+        }
+        (Some("SlowDown" | "Throttling" | "ThrottlingException"), _) | (_, Some(429)) => {
+            TypedError::S3Throttled(key, Box::new(err))
+        }
+        (Some("RequestTimeout"), _) => TypedError::S3Timeout(key, Box::new(err)),
+        _ if timed_out => TypedError::S3Timeout(key, Box::new(err)),
+        (_, Some(500..)) => TypedError::S3Unavailable(key, Box::new(err)),
+        _ if unavailable => TypedError::S3Unavailable(key, Box::new(err)),
+        _ => other(key, Box::new(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location() -> S3Location {
+        S3Location {
+            bucket: "photos".to_string(),
+            key: "cat.jpg".to_string(),
+        }
+    }
+
+    fn resolve(path: &str, bucket: Option<&str>) -> Result<S3Location, TypedError> {
+        S3Location::resolve(path, bucket, Some("default"))
+    }
+
+    #[test]
+    fn locations_resolve_uris_and_keys() {
+        let uri = resolve("s3://photos/cats/cat.jpg", None).unwrap();
+        assert_eq!(
+            (uri.bucket.as_str(), uri.key.as_str()),
+            ("photos", "cats/cat.jpg")
+        );
+        // a bucket naming the URI's own is allowed.
+        let uri = resolve("s3://photos/cat.jpg", Some("photos")).unwrap();
+        assert_eq!(uri.to_string(), "s3://photos/cat.jpg");
+
+        let key = resolve("cats/cat.jpg", None).unwrap();
+        assert_eq!(key.to_string(), "s3://default/cats/cat.jpg");
+        let key = resolve("cat.jpg", Some("photos")).unwrap();
+        assert_eq!(key.to_string(), "s3://photos/cat.jpg");
+        let err = S3Location::resolve("cat.jpg", None, None).unwrap_err();
+        assert_eq!(err.code(), "missing_bucket");
+    }
+
+    #[test]
+    fn keys_are_taken_as_given() {
+        // request keys are never URL-decoded, only keys from S3 events are.
+        let key = resolve("my%20cat+1.jpg", None).unwrap();
+        assert_eq!(key.key, "my%20cat+1.jpg");
+        let object = crate::events::S3Object {
+            key: "my+cat%281%29.jpg".to_string(),
+        };
+        let key = resolve(&object.decoded_key(), None).unwrap();
+        assert_eq!(key.key, "my cat(1).jpg");
+    }
+
+    #[test]
+    fn invalid_locations_are_refused() {
+        for path in ["", "s3://", "s3://photos", "s3://photos/", "s3:///cat.jpg"] {
+            let err = resolve(path, None).unwrap_err();
+            assert_eq!(err.code(), "invalid_path", "{path}");
+        }
+        let err = resolve("s3://photos/cat.jpg", Some("other")).unwrap_err();
+        assert_eq!(err.code(), "invalid_path");
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_cap() {
+        let retry = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+        let longest = |attempt| (0..200).map(|_| retry.backoff(attempt)).max().unwrap();
+        // the jitter stays under a ceiling that doubles for each attempt.
+        for (attempt, ceiling) in [(1, 100), (2, 200), (3, 400), (4, 800)] {
+            let longest = longest(attempt);
+            assert!(
+                longest <= Duration::from_millis(ceiling),
+                "{attempt}: {longest:?}"
+            );
+            assert!(
+                longest > Duration::from_millis(ceiling / 2),
+                "{attempt}: {longest:?}"
+            );
+        }
+        for attempt in [5, 40, u32::MAX] {
+            let longest = longest(attempt);
+            assert!(longest <= retry.max_delay, "{attempt}: {longest:?}");
+            assert!(
+                longest > Duration::from_millis(500),
+                "{attempt}: {longest:?}"
+            );
+        }
+    }
+
+    const RETRY: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(1),
+    };
+
+    /// Fetches that fail with `error` the first `failures` times, and each
+    /// take 1 second of request time.
+    async fn fetch_failing(
+        failures: u32,
+        error: fn() -> TypedError,
+        deadline: Option<Instant>,
+    ) -> (Result<FetchedObject, TypedError>, u32) {
+        let mut calls = 0;
+        let result = with_retries(&location(), &RETRY, deadline, || {
+            calls += 1;
+            let result = match calls <= failures {
+                true => Err(error()),
+                false => Ok(FetchedObject {
+                    bytes: Bytes::from_static(b"cat"),
+                    version: ObjectVersion::default(),
+                    attempts: 1,
+                    timings: Timings::default(),
+                }),
+            };
+            let timings = Timings {
+                request: 1.0,
+                ..Timings::default()
+            };
+            async move { (result, timings) }
+        })
+        .await;
+        (result, calls)
+    }
+
+    fn throttled() -> TypedError {
+        TypedError::S3Throttled("s3://photos/cat.jpg".to_string(), "slow down".into())
+    }
+
+    #[tokio::test]
+    async fn retryable_failures_are_retried() {
+        let (object, calls) = fetch_failing(2, throttled, None).await;
+        let object = object.unwrap();
+        assert_eq!((object.attempts, calls), (3, 3));
+        assert_eq!(object.timings.request, 3.0);
+        assert_eq!(object.timings.bytes, 3);
+
+        let (err, calls) = fetch_failing(3, throttled, None).await;
+        assert_eq!(err.err().unwrap().code(), "throttled");
+        assert_eq!(calls, 3);
+
+        let not_found = || TypedError::NotFound("s3://photos/cat.jpg".to_string(), None);
+        let (err, calls) = fetch_failing(1, not_found, None).await;
+        assert_eq!(err.err().unwrap().code(), "not_found");
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn attempts_are_cut_short_at_the_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let mut calls = 0;
+        let result = with_retries(&location(), &RETRY, Some(deadline), || {
+            calls += 1;
+            async {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                (Err(throttled()), Timings::default())
+            }
+        })
+        .await;
+        // the attempt timed out, and there is no time left to retry it.
+        assert_eq!(result.err().unwrap().code(), "s3_timeout");
+        assert_eq!(calls, 1);
+        assert!(Instant::now() < deadline + Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn bodies_are_limited_without_a_length() {
+        let body = || ByteStream::from(vec![7; 100]);
+        let data = read_body(body(), &location(), None, 100).await.unwrap();
+        assert_eq!(data.len(), 100);
+        let err = read_body(body(), &location(), None, 99).await.unwrap_err();
+        assert_eq!(err.code(), "object_too_large");
+        assert_eq!(err.key(), Some("s3://photos/cat.jpg"));
+        // a body longer than its reported length is cut off all the same.
+        let err = read_body(body(), &location(), Some(10), 99)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "object_too_large");
+    }
+}
@@ -0,0 +1,282 @@
+//! Where images are read from: S3, a local directory, or memory.
+
+use crate::hash::{decode_image_timed, DecodeLimits};
+use crate::orientation::{read_orientation, Orientation};
+use crate::s3::{fetch_object, head_object, FetchedObject, ObjectVersion, RetryPolicy, S3Location};
+use crate::timings::Timings;
+use crate::TypedError;
+use aws_sdk_s3::primitives::DateTime;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use image::DynamicImage;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+
+/// A store that objects are read from, addressed by bucket and key.
+pub trait ImageSource: Send + Sync {
+    /// Reads the whole object at `location`, failing with
+    /// [`TypedError::ObjectTooLarge`] without reading it if it is larger
+    /// than `max_bytes`. Sources that retry stop doing so at `deadline`.
+    fn fetch<'a>(
+        &'a self,
+        location: &'a S3Location,
+        max_bytes: u64,
+        deadline: Option<Instant>,
+    ) -> BoxFuture<'a, Result<FetchedObject, TypedError>>;
+
+    /// The current version of the object at `location`, without reading it.
+    fn head<'a>(
+        &'a self,
+        location: &'a S3Location,
+    ) -> BoxFuture<'a, Result<ObjectVersion, TypedError>>;
+}
+
+/// A decoded image and the object version it came from.
+pub struct FetchedImage {
+    pub image: DynamicImage,
+    /// The encoded image, for decoding more than the first frame.
+    pub bytes: Bytes,
+    pub version: ObjectVersion,
+    /// How many attempts it took to read the object.
+    pub attempts: u32,
+    /// Time spent fetching and decoding the image so far.
+    pub timings: Timings,
+    /// The EXIF orientation of the image, not applied yet.
+    pub orientation: Option<Orientation>,
+}
+
+impl FetchedImage {
+    /// Turns the image upright if `enabled` and it has an EXIF orientation,
+    /// returning the orientation applied.
+    pub fn auto_orient(&mut self, enabled: bool) -> Option<Orientation> {
+        let orientation = self.orientation.filter(|_| enabled)?;
+        self.image = orientation.apply(std::mem::take(&mut self.image));
+        Some(orientation)
+    }
+}
+
+/// Reads an object from `source` and decodes it as an image within `limits`.
+pub async fn fetch_image(
+    source: &dyn ImageSource,
+    location: &S3Location,
+    limits: &DecodeLimits,
+    deadline: Option<Instant>,
+) -> Result<FetchedImage, TypedError> {
+    let mut object = source
+        .fetch(location, limits.max_object_bytes, deadline)
+        .await?;
+    Ok(FetchedImage {
+        image: decode_image_timed(&object.bytes, limits, &mut object.timings)?,
+        version: object.version,
+        attempts: object.attempts,
+        timings: object.timings,
+        orientation: read_orientation(&object.bytes),
+        bytes: object.bytes,
+    })
+}
+
+/// Reads objects from S3.
+pub struct S3Source {
+    pub s3_client: aws_sdk_s3::Client,
+    pub retry: RetryPolicy,
+}
+
+impl ImageSource for S3Source {
+    fn fetch<'a>(
+        &'a self,
+        location: &'a S3Location,
+        max_bytes: u64,
+        deadline: Option<Instant>,
+    ) -> BoxFuture<'a, Result<FetchedObject, TypedError>> {
+        Box::pin(fetch_object(
+            &self.s3_client,
+            location,
+            max_bytes,
+            &self.retry,
+            deadline,
+        ))
+    }
+
+    fn head<'a>(
+        &'a self,
+        location: &'a S3Location,
+    ) -> BoxFuture<'a, Result<ObjectVersion, TypedError>> {
+        Box::pin(head_object(&self.s3_client, location))
+    }
+}
+
+/// Reads objects from `<root>/<bucket>/<key>` on the local filesystem.
+pub struct LocalSource {
+    pub root: PathBuf,
+}
+
+impl LocalSource {
+    /// The file backing `location`, refusing keys that would escape `root`.
+    fn path(&self, location: &S3Location) -> Result<PathBuf, TypedError> {
+        let relative = Path::new(&location.bucket).join(&location.key);
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(TypedError::InvalidPath(location.to_string()));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl ImageSource for LocalSource {
+    fn fetch<'a>(
+        &'a self,
+        location: &'a S3Location,
+        max_bytes: u64,
+        _deadline: Option<Instant>,
+    ) -> BoxFuture<'a, Result<FetchedObject, TypedError>> {
+        Box::pin(async move {
+            let path = self.path(location)?;
+            let read_error = |err| TypedError::from_io(location.to_string(), err);
+            let start = Instant::now();
+            let metadata = tokio::fs::metadata(&path).await.map_err(read_error)?;
+            if metadata.len() > max_bytes {
+                return Err(TypedError::ObjectTooLarge(
+                    location.to_string(),
+                    metadata.len(),
+                    max_bytes,
+                ));
+            }
+            let bytes = tokio::fs::read(&path).await.map_err(read_error)?;
+            let mut timings = Timings {
+                bytes: bytes.len() as u64,
+                ..Timings::default()
+            };
+            Timings::add(&mut timings.download, start);
+            tracing::info!(path = %path.display(), "data successfully read from disk");
+            Ok(FetchedObject {
+                bytes: Bytes::from(bytes),
+                version: ObjectVersion {
+                    last_modified: metadata.modified().ok().map(DateTime::from),
+                    ..ObjectVersion::default()
+                },
+                attempts: 1,
+                timings,
+            })
+        })
+    }
+
+    fn head<'a>(
+        &'a self,
+        location: &'a S3Location,
+    ) -> BoxFuture<'a, Result<ObjectVersion, TypedError>> {
+        Box::pin(async move {
+            let path = self.path(location)?;
+            let metadata = tokio::fs::metadata(&path)
+                .await
+                .map_err(|err| TypedError::from_io(location.to_string(), err))?;
+            Ok(ObjectVersion {
+                last_modified: metadata.modified().ok().map(DateTime::from),
+                ..ObjectVersion::default()
+            })
+        })
+    }
+}
+
+/// Serves objects from a map, for tests and local experiments.
+///
+/// Objects get an ETag that changes with their contents, as in S3, so their
+/// results can be cached.
+#[derive(Default)]
+pub struct MemorySource {
+    objects: HashMap<S3Location, (Bytes, ObjectVersion)>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces the object at `location`.
+    pub fn insert(&mut self, location: S3Location, bytes: impl Into<Bytes>) {
+        let bytes = bytes.into();
+        let version = ObjectVersion {
+            e_tag: Some(format!("\"{}\"", hex::encode(Sha256::digest(&bytes)))),
+            ..ObjectVersion::default()
+        };
+        self.objects.insert(location, (bytes, version));
+    }
+}
+
+impl ImageSource for MemorySource {
+    fn fetch<'a>(
+        &'a self,
+        location: &'a S3Location,
+        max_bytes: u64,
+        _deadline: Option<Instant>,
+    ) -> BoxFuture<'a, Result<FetchedObject, TypedError>> {
+        Box::pin(async move {
+            let (bytes, version) = self
+                .objects
+                .get(location)
+                .cloned()
+                .ok_or_else(|| TypedError::NotFound(location.to_string(), None))?;
+            if bytes.len() as u64 > max_bytes {
+                return Err(TypedError::ObjectTooLarge(
+                    location.to_string(),
+                    bytes.len() as u64,
+                    max_bytes,
+                ));
+            }
+            let timings = Timings {
+                bytes: bytes.len() as u64,
+                ..Timings::default()
+            };
+            Ok(FetchedObject {
+                bytes,
+                version,
+                attempts: 1,
+                timings,
+            })
+        })
+    }
+
+    fn head<'a>(
+        &'a self,
+        location: &'a S3Location,
+    ) -> BoxFuture<'a, Result<ObjectVersion, TypedError>> {
+        Box::pin(async move {
+            match self.objects.get(location) {
+                Some((_, version)) => Ok(version.clone()),
+                None => Err(TypedError::NotFound(location.to_string(), None)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_objects_are_limited() {
+        let root = std::env::temp_dir().join(format!("image-hash-source-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("photos")).unwrap();
+        std::fs::write(root.join("photos/cat.jpg"), [7; 100]).unwrap();
+        let source = LocalSource { root: root.clone() };
+        let location = S3Location {
+            bucket: "photos".to_string(),
+            key: "cat.jpg".to_string(),
+        };
+
+        let object = source.fetch(&location, 100, None).await.unwrap();
+        assert_eq!(object.bytes.len(), 100);
+        let err = source.fetch(&location, 99, None).await.err().unwrap();
+        assert_eq!(err.code(), "object_too_large");
+        let escape = S3Location {
+            bucket: "photos".to_string(),
+            key: "../../etc/passwd".to_string(),
+        };
+        let err = source.fetch(&escape, 100, None).await.err().unwrap();
+        assert_eq!(err.code(), "invalid_path");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! Where the time handling an image goes, reported in responses and as
+//! tracing span fields.
+
+use serde::Serialize;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::Span;
+
+/// How long each stage of handling one image took, in seconds like
+/// [`HashResult::time_elapsed`](crate::HashResult::time_elapsed). Stages
+/// that didn't happen, like downloading an inline image, stay zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Timings {
+    /// Waiting for the response to the S3 `GetObject` or URL request, over
+    /// every attempt.
+    pub request: f64,
+    /// Reading the response body, over every attempt.
+    pub download: f64,
+    /// Guessing the format and reading the dimensions from the header.
+    pub format_detection: f64,
+    pub decode: f64,
+    /// Hashing with every requested algorithm.
+    pub hash: f64,
+    /// Wall time of the whole request, including index lookups and
+    /// write-backs.
+    pub total: f64,
+    /// Size of the encoded image.
+    pub bytes: u64,
+}
+
+impl Timings {
+    /// Adds the time since `start` to `stage`.
+    pub fn add(stage: &mut f64, start: Instant) {
+        *stage += start.elapsed().as_secs_f64();
+    }
+
+    /// Records every timing on a span created by [`span`].
+    pub fn record(&self, span: &Span) {
+        span.record("request", self.request);
+        span.record("download", self.download);
+        span.record("format_detection", self.format_detection);
+        span.record("decode", self.decode);
+        span.record("hash", self.hash);
+        span.record("total", self.total);
+        span.record("bytes", self.bytes);
+    }
+}
+
+/// A span for handling one image, with a field for each of its [`Timings`].
+pub fn span() -> Span {
+    tracing::info_span!(
+        "image",
+        request = Empty,
+        download = Empty,
+        format_detection = Empty,
+        decode = Empty,
+        hash = Empty,
+        total = Empty,
+        bytes = Empty,
+    )
+}
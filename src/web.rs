@@ -0,0 +1,457 @@
+//! Downloading images from HTTP(S) URLs, for images served by CDNs rather
+//! than stored in S3.
+
+use crate::config::{env_millis, env_or};
+use crate::hash::{decode_image_timed, DecodeLimits};
+use crate::orientation::read_orientation;
+use crate::s3::{FetchedObject, ObjectVersion};
+use crate::source::FetchedImage;
+use crate::timings::Timings;
+use crate::TypedError;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use bytes::Bytes;
+use reqwest::header::{CONTENT_TYPE, ETAG, LAST_MODIFIED, LOCATION};
+use reqwest::{redirect, Url};
+use std::time::{Duration, Instant};
+
+/// What may be downloaded, and how long to wait for it.
+#[derive(Debug, Clone)]
+pub struct UrlConfig {
+    /// Hosts images may be downloaded from, where `*.example.com` allows
+    /// every subdomain of `example.com`. URLs are refused while it is empty.
+    pub allowed_hosts: Vec<String>,
+    pub connect_timeout: Duration,
+    /// Longest wait for each read of the response, not the whole download.
+    pub read_timeout: Duration,
+    /// Largest response body, in bytes.
+    pub max_bytes: usize,
+    pub max_redirects: usize,
+}
+
+impl Default for UrlConfig {
+    /// No allowed hosts, so every URL is refused.
+    fn default() -> Self {
+        UrlConfig {
+            allowed_hosts: Vec::new(),
+            connect_timeout: Duration::from_secs(2),
+            read_timeout: Duration::from_secs(10),
+            max_bytes: 20 * 1024 * 1024,
+            max_redirects: 3,
+        }
+    }
+}
+
+impl UrlConfig {
+    pub fn from_env() -> Self {
+        let defaults = UrlConfig::default();
+        let allowed_hosts = std::env::var("URL_ALLOWED_HOSTS")
+            .map(|hosts| {
+                hosts
+                    .split(',')
+                    .map(|host| host.trim().to_ascii_lowercase())
+                    .filter(|host| !host.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        UrlConfig {
+            allowed_hosts,
+            connect_timeout: env_millis("URL_CONNECT_TIMEOUT_MS", defaults.connect_timeout),
+            read_timeout: env_millis("URL_READ_TIMEOUT_MS", defaults.read_timeout),
+            max_bytes: env_or("URL_MAX_BYTES", defaults.max_bytes),
+            max_redirects: env_or("URL_MAX_REDIRECTS", defaults.max_redirects),
+        }
+    }
+
+    /// Whether `url` is HTTP(S) on an allowed host.
+    fn allows(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        matches!(url.scheme(), "http" | "https")
+            && self
+                .allowed_hosts
+                .iter()
+                .any(|allowed| match allowed.strip_prefix("*.") {
+                    Some(domain) => host
+                        .strip_suffix(domain)
+                        .is_some_and(|sub| sub.ends_with('.')),
+                    None => host == allowed,
+                })
+    }
+}
+
+/// Parses the `url` of a request, which must be absolute HTTP(S).
+pub fn parse_url(url: &str) -> Result<Url, TypedError> {
+    Url::parse(url)
+        .ok()
+        .filter(|parsed| matches!(parsed.scheme(), "http" | "https"))
+        .ok_or_else(|| TypedError::InvalidUrl(url.to_string()))
+}
+
+/// Downloads images within the limits of a [`UrlConfig`].
+pub struct UrlFetcher {
+    client: reqwest::Client,
+    config: UrlConfig,
+}
+
+impl UrlFetcher {
+    pub fn new(config: UrlConfig) -> Self {
+        // redirects are followed by hand, to check each hop's host.
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .read_timeout(config.read_timeout)
+            .redirect(redirect::Policy::none())
+            .build()
+            .expect("failed to build the HTTP client");
+        UrlFetcher { client, config }
+    }
+
+    /// Downloads the whole body at `url`, making sure it looks like an image.
+    /// Gives up at `deadline`, if given, however far the download got.
+    pub async fn fetch(
+        &self,
+        url: &Url,
+        deadline: Option<Instant>,
+    ) -> Result<FetchedObject, TypedError> {
+        let Some(deadline) = deadline else {
+            return self.download(url).await;
+        };
+        tokio::time::timeout_at(deadline.into(), self.download(url))
+            .await
+            .unwrap_or_else(|_| {
+                tracing::error!(url = %url, "gave up downloading the image at the deadline");
+                Err(TypedError::UrlTimeout(url.to_string()))
+            })
+    }
+
+    async fn download(&self, url: &Url) -> Result<FetchedObject, TypedError> {
+        let mut current = url.clone();
+        let mut redirects = 0;
+        let mut timings = Timings::default();
+        let start = Instant::now();
+        let mut response = loop {
+            if !self.config.allows(&current) {
+                return Err(TypedError::UrlNotAllowed(current.to_string()));
+            }
+            let response = self
+                .client
+                .get(current.clone())
+                .send()
+                .await
+                .map_err(|e| request_error(&current, e))?;
+            if !response.status().is_redirection() {
+                break response;
+            }
+            let next = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| current.join(location).ok())
+                .ok_or_else(|| {
+                    TypedError::UrlFetch(
+                        current.to_string(),
+                        format!("HTTP {} without a valid Location", response.status()).into(),
+                    )
+                })?;
+            redirects += 1;
+            if redirects > self.config.max_redirects {
+                return Err(TypedError::TooManyRedirects(url.to_string()));
+            }
+            current = next;
+        };
+        Timings::add(&mut timings.request, start);
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(TypedError::UrlStatus(current.to_string(), status.as_u16()));
+        }
+        let max_bytes = self.config.max_bytes;
+        if response
+            .content_length()
+            .is_some_and(|length| length > max_bytes as u64)
+        {
+            return Err(TypedError::UrlTooLarge(current.to_string(), max_bytes));
+        }
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let content_type = header(CONTENT_TYPE);
+        let version = ObjectVersion {
+            e_tag: header(ETAG),
+            version_id: None,
+            last_modified: header(LAST_MODIFIED)
+                .and_then(|value| DateTime::from_str(&value, DateTimeFormat::HttpDate).ok()),
+        };
+
+        let start = Instant::now();
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| request_error(&current, e))?
+        {
+            if body.len() + chunk.len() > max_bytes {
+                return Err(TypedError::UrlTooLarge(current.to_string(), max_bytes));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Timings::add(&mut timings.download, start);
+        timings.bytes = body.len() as u64;
+
+        // CDNs often get content types wrong, so the bytes decide; the header
+        // is only reported when they don't look like any image format.
+        if image::guess_format(&body).is_err() {
+            return Err(TypedError::NotAnImage(
+                current.to_string(),
+                content_type.unwrap_or_else(|| "none".to_string()),
+            ));
+        }
+        tracing::info!(
+            url = %current,
+            bytes = body.len(),
+            redirects,
+            "data successfully downloaded",
+        );
+        Ok(FetchedObject {
+            bytes: Bytes::from(body),
+            version,
+            attempts: 1,
+            timings,
+        })
+    }
+
+    /// Downloads and decodes the image at `url` within `limits`, besides
+    /// the download limits of the fetcher's own config, giving up on the
+    /// download at `deadline`.
+    pub async fn fetch_image(
+        &self,
+        url: &Url,
+        limits: &DecodeLimits,
+        deadline: Option<Instant>,
+    ) -> Result<FetchedImage, TypedError> {
+        let mut object = self.fetch(url, deadline).await?;
+        Ok(FetchedImage {
+            image: decode_image_timed(&object.bytes, limits, &mut object.timings)?,
+            version: object.version,
+            attempts: object.attempts,
+            timings: object.timings,
+            orientation: read_orientation(&object.bytes),
+            bytes: object.bytes,
+        })
+    }
+}
+
+fn request_error(url: &Url, err: reqwest::Error) -> TypedError {
+    tracing::error!(err = %err, url = %url, "failed to download image");
+    if err.is_timeout() {
+        TypedError::UrlTimeout(url.to_string())
+    } else {
+        TypedError::UrlFetch(url.to_string(), Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// The smallest GIF, which is enough to look like an image.
+    const GIF: &[u8] = b"GIF89a\x01\x00\x01\x00\x00\x00\x00;";
+
+    fn config(allowed_hosts: &[&str]) -> UrlConfig {
+        UrlConfig {
+            allowed_hosts: allowed_hosts.iter().map(|host| host.to_string()).collect(),
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            max_bytes: 64,
+            max_redirects: 2,
+        }
+    }
+
+    /// Serves the routes of the tests on a local port, returning its base
+    /// URL on `127.0.0.1`.
+    async fn serve() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let read = socket.read(&mut buffer).await.unwrap();
+                        if read == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buffer[..read]);
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let path = request.split(' ').nth(1).unwrap_or("/").to_string();
+                    let response = respond(&path, port).await;
+                    let _ = socket.write_all(&response).await;
+                });
+            }
+        });
+        Url::parse(&format!("http://127.0.0.1:{port}/")).unwrap()
+    }
+
+    async fn respond(path: &str, port: u16) -> Vec<u8> {
+        let with_body = |head: &str, body: &[u8]| {
+            let mut response = format!("HTTP/1.1 {head}\r\nconnection: close\r\n\r\n").into_bytes();
+            response.extend_from_slice(body);
+            response
+        };
+        let redirect = |location: String| {
+            with_body(
+                &format!("302 Found\r\nlocation: {location}\r\ncontent-length: 0"),
+                b"",
+            )
+        };
+        match path {
+            "/image" => with_body(
+                &format!(
+                    "200 OK\r\ncontent-type: text/plain\r\ncontent-length: {}",
+                    GIF.len()
+                ),
+                GIF,
+            ),
+            "/text" => with_body(
+                "200 OK\r\ncontent-type: text/html\r\ncontent-length: 5",
+                b"hello",
+            ),
+            "/large" => with_body("200 OK\r\ncontent-length: 100", &[0; 100]),
+            // no length, so only counting the body as it arrives catches it.
+            "/large-unsized" => with_body("200 OK", &[0; 100]),
+            "/missing" => with_body("404 Not Found\r\ncontent-length: 0", b""),
+            "/elsewhere" => redirect(format!("http://localhost:{port}/image")),
+            "/slow" => {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                with_body("200 OK\r\ncontent-length: 0", b"")
+            }
+            path => match path
+                .strip_prefix("/hops/")
+                .and_then(|n| n.parse::<u32>().ok())
+            {
+                Some(0) => redirect("/image".to_string()),
+                Some(hops) => redirect(format!("/hops/{}", hops - 1)),
+                None => with_body("404 Not Found\r\ncontent-length: 0", b""),
+            },
+        }
+    }
+
+    async fn fetch(config: UrlConfig, url: &Url) -> Result<FetchedObject, TypedError> {
+        UrlFetcher::new(config).fetch(url, None).await
+    }
+
+    fn code(result: Result<FetchedObject, TypedError>) -> &'static str {
+        match result {
+            Ok(_) => "ok",
+            Err(err) => err.code(),
+        }
+    }
+
+    #[test]
+    fn wildcards_only_match_subdomains() {
+        let config = config(&["*.example.com", "images.test"]);
+        let allows = |url: &str| config.allows(&Url::parse(url).unwrap());
+        assert!(allows("https://cdn.example.com/cat.jpg"));
+        assert!(allows("https://a.b.example.com/cat.jpg"));
+        assert!(allows("http://images.test/cat.jpg"));
+        assert!(!allows("https://example.com/cat.jpg"));
+        assert!(!allows("https://evilexample.com/cat.jpg"));
+        assert!(!allows("https://cdn.example.com.evil.test/cat.jpg"));
+        assert!(!allows("https://sub.images.test/cat.jpg"));
+        assert!(!allows("ftp://cdn.example.com/cat.jpg"));
+        assert!(!UrlConfig {
+            allowed_hosts: Vec::new(),
+            ..config.clone()
+        }
+        .allows(&Url::parse("https://cdn.example.com/").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn images_are_downloaded_whatever_their_content_type() {
+        let base = serve().await;
+        let object = fetch(config(&["127.0.0.1"]), &base.join("image").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(object.bytes.as_ref(), GIF);
+        assert_eq!(object.timings.bytes, GIF.len() as u64);
+
+        let err = fetch(config(&["127.0.0.1"]), &base.join("text").unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&err, TypedError::NotAnImage(_, content_type) if content_type == "text/html")
+        );
+
+        let missing = fetch(config(&["127.0.0.1"]), &base.join("missing").unwrap()).await;
+        assert!(matches!(missing, Err(TypedError::UrlStatus(_, 404))));
+    }
+
+    #[tokio::test]
+    async fn hosts_are_checked_on_every_hop() {
+        let base = serve().await;
+        let url = base.join("image").unwrap();
+        assert_eq!(
+            code(fetch(config(&["localhost"]), &url).await),
+            "url_not_allowed"
+        );
+        let url = base.join("elsewhere").unwrap();
+        assert_eq!(
+            code(fetch(config(&["127.0.0.1"]), &url).await),
+            "url_not_allowed"
+        );
+        let both = config(&["127.0.0.1", "localhost"]);
+        assert_eq!(code(fetch(both, &url).await), "ok");
+    }
+
+    #[tokio::test]
+    async fn redirects_are_limited() {
+        let base = serve().await;
+        let url = base.join("hops/1").unwrap();
+        assert_eq!(code(fetch(config(&["127.0.0.1"]), &url).await), "ok");
+        let url = base.join("hops/2").unwrap();
+        assert_eq!(
+            code(fetch(config(&["127.0.0.1"]), &url).await),
+            "too_many_redirects"
+        );
+    }
+
+    #[tokio::test]
+    async fn bodies_are_limited_with_and_without_a_length() {
+        let base = serve().await;
+        for path in ["large", "large-unsized"] {
+            let url = base.join(path).unwrap();
+            assert_eq!(
+                code(fetch(config(&["127.0.0.1"]), &url).await),
+                "url_too_large",
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_responses_time_out() {
+        let base = serve().await;
+        let url = base.join("slow").unwrap();
+        let short = UrlConfig {
+            read_timeout: Duration::from_millis(100),
+            ..config(&["127.0.0.1"])
+        };
+        assert_eq!(code(fetch(short, &url).await), "url_timeout");
+
+        let fetcher = UrlFetcher::new(config(&["127.0.0.1"]));
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let result = fetcher.fetch(&url, Some(deadline)).await;
+        assert_eq!(code(result), "url_timeout");
+        assert!(Instant::now() < deadline + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,315 @@
+//! Persisting computed hashes next to the objects they were computed from.
+
+use crate::hash::HashResult;
+use crate::options::HashConfig;
+use crate::s3::{s3_error, ObjectVersion, S3Location};
+use crate::TypedError;
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::primitives::{ByteStream, DateTime, DateTimeFormat};
+use aws_sdk_s3::types::{Tag, Tagging};
+use image_hasher::HashAlg;
+use serde::{Deserialize, Serialize};
+
+/// Tag holding the algorithm of the hash written back.
+const TAG_ALGO: &str = "image-hash-algo";
+/// Tag holding the base64 hash.
+const TAG_HASH: &str = "image-hash";
+/// Tag holding the image dimensions as `WIDTHxHEIGHT`.
+const TAG_SIZE: &str = "image-hash-size";
+/// S3 allows at most this many tags per object.
+const MAX_TAGS: usize = 10;
+/// S3 allows at most this many characters in a tag value.
+const MAX_TAG_VALUE: usize = 256;
+
+/// Where computed hashes are written back to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteBackMode {
+    /// Don't write anything back, even if enabled by default.
+    None,
+    /// Tag the hashed object itself with the first hash, its algorithm and
+    /// the image size.
+    Tags,
+    /// Write every hash and the hasher settings as JSON to
+    /// `<prefix><key>.json` in the same bucket.
+    Sidecar,
+}
+
+impl std::str::FromStr for WriteBackMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(WriteBackMode::None),
+            "tags" => Ok(WriteBackMode::Tags),
+            "sidecar" => Ok(WriteBackMode::Sidecar),
+            _ => Err(format!("unknown write back mode `{s}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteBackStatus {
+    Written,
+    /// A result for a newer version of the object was already there, or
+    /// the object changed since it was downloaded, so nothing was written.
+    Stale,
+    /// Writing failed, for the reason given next to the status; the hashes
+    /// are still good.
+    Failed,
+}
+
+/// Fails if the primary hash, computed with `algo` and `config`, can't be
+/// written back with `mode`, before anything is downloaded or hashed.
+pub fn validate(mode: WriteBackMode, algo: HashAlg, config: &HashConfig) -> Result<(), TypedError> {
+    if mode != WriteBackMode::Tags {
+        return Ok(());
+    }
+    // only the primary hash is tagged, written as unpadded base64.
+    let bytes = (config.hash_bits(algo) as usize).div_ceil(8);
+    let length = (bytes * 4).div_ceil(3);
+    if length > MAX_TAG_VALUE {
+        return Err(TypedError::InvalidRequest(format!(
+            "a {}x{} {algo:?} hash is {length} characters, over the {MAX_TAG_VALUE} S3 allows \
+             in a tag; write it back as a `sidecar` instead",
+            config.hash_width, config.hash_height
+        )));
+    }
+    Ok(())
+}
+
+/// The sidecar object's contents.
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    source: String,
+    e_tag: Option<&'a str>,
+    version_id: Option<&'a str>,
+    last_modified: Option<String>,
+    hash: &'a HashResult,
+}
+
+/// The part of an existing sidecar needed to tell whether it is newer.
+#[derive(Deserialize)]
+struct SidecarVersion {
+    last_modified: Option<String>,
+}
+
+pub async fn write_back(
+    s3_client: &aws_sdk_s3::Client,
+    mode: WriteBackMode,
+    sidecar_prefix: &str,
+    location: &S3Location,
+    version: &ObjectVersion,
+    hash: &HashResult,
+) -> Result<Option<WriteBackStatus>, TypedError> {
+    let status = match mode {
+        WriteBackMode::None => return Ok(None),
+        WriteBackMode::Tags => write_tags(s3_client, location, version, hash).await?,
+        WriteBackMode::Sidecar => {
+            write_sidecar(s3_client, sidecar_prefix, location, version, hash).await?
+        }
+    };
+    tracing::info!(
+        bucket = %location.bucket,
+        key = %location.key,
+        mode = ?mode,
+        status = ?status,
+        "hash written back to S3",
+    );
+    Ok(Some(status))
+}
+
+/// Adds the hash tags to the object, keeping its other tags.
+///
+/// On versioned buckets the exact version that was hashed is tagged. Otherwise
+/// the write is skipped if the object's ETag changed since it was downloaded,
+/// leaving the tags to the invocation hashing the newer upload.
+async fn write_tags(
+    s3_client: &aws_sdk_s3::Client,
+    location: &S3Location,
+    version: &ObjectVersion,
+    hash: &HashResult,
+) -> Result<WriteBackStatus, TypedError> {
+    if version.version_id.is_none() {
+        let head = s3_client
+            .head_object()
+            .bucket(&location.bucket)
+            .key(&location.key)
+            .send()
+            .await
+            .map_err(|e| put_error(location, e))?;
+        if head.e_tag() != version.e_tag.as_deref() {
+            return Ok(WriteBackStatus::Stale);
+        }
+    }
+
+    let existing = s3_client
+        .get_object_tagging()
+        .bucket(&location.bucket)
+        .key(&location.key)
+        .set_version_id(version.version_id.clone())
+        .send()
+        .await
+        .map_err(|e| put_error(location, e))?;
+    let (width, height) = hash.image_size;
+    let ours = [
+        (TAG_ALGO, format!("{:?}", hash.algo)),
+        (TAG_HASH, hash.hash_base64.clone()),
+        (TAG_SIZE, format!("{width}x{height}")),
+    ];
+    let mut tags: Vec<Tag> = existing
+        .tag_set()
+        .iter()
+        .filter(|tag| !ours.iter().any(|(key, _)| tag.key() == *key))
+        .cloned()
+        .collect();
+    for (key, value) in ours {
+        tags.push(
+            Tag::builder()
+                .key(key)
+                .value(value)
+                .build()
+                .map_err(|e| TypedError::S3Put(location.to_string(), Box::new(e)))?,
+        );
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(TypedError::S3Put(
+            location.to_string(),
+            format!("too many tags to add the hash to, at most {MAX_TAGS} are allowed").into(),
+        ));
+    }
+
+    let tagging = Tagging::builder()
+        .set_tag_set(Some(tags))
+        .build()
+        .map_err(|e| TypedError::S3Put(location.to_string(), Box::new(e)))?;
+    s3_client
+        .put_object_tagging()
+        .bucket(&location.bucket)
+        .key(&location.key)
+        .set_version_id(version.version_id.clone())
+        .tagging(tagging)
+        .send()
+        .await
+        .map_err(|e| put_error(location, e))?;
+    Ok(WriteBackStatus::Written)
+}
+
+/// Writes the sidecar JSON, unless the one already there was computed from a
+/// more recently modified object.
+///
+/// S3 has no conditional puts here, so two invocations racing on the same key
+/// can still both write; the check only stops late re-runs from clobbering.
+async fn write_sidecar(
+    s3_client: &aws_sdk_s3::Client,
+    prefix: &str,
+    location: &S3Location,
+    version: &ObjectVersion,
+    hash: &HashResult,
+) -> Result<WriteBackStatus, TypedError> {
+    let sidecar = S3Location {
+        bucket: location.bucket.clone(),
+        key: format!("{prefix}{}.json", location.key),
+    };
+
+    let existing = s3_client
+        .get_object()
+        .bucket(&sidecar.bucket)
+        .key(&sidecar.key)
+        .send()
+        .await;
+    match existing {
+        Ok(output) => {
+            let data = output
+                .body
+                .collect()
+                .await
+                .map_err(|e| TypedError::S3Download(sidecar.to_string(), Box::new(e)))?;
+            let existing_modified = serde_json::from_slice::<SidecarVersion>(&data.into_bytes())
+                .ok()
+                .and_then(|v| v.last_modified)
+                .and_then(|v| DateTime::from_str(&v, DateTimeFormat::DateTime).ok());
+            if let (Some(existing), Some(ours)) = (existing_modified, version.last_modified) {
+                if existing > ours {
+                    return Ok(WriteBackStatus::Stale);
+                }
+            }
+        }
+        Err(err) if err.as_service_error().is_some_and(|e| e.is_no_such_key()) => {}
+        Err(err) => return Err(put_error(&sidecar, err)),
+    }
+
+    let body = Sidecar {
+        source: location.to_string(),
+        e_tag: version.e_tag.as_deref(),
+        version_id: version.version_id.as_deref(),
+        last_modified: version
+            .last_modified
+            .and_then(|v| v.fmt(DateTimeFormat::DateTime).ok()),
+        hash,
+    };
+    let body = serde_json::to_vec(&body)
+        .map_err(|e| TypedError::S3Put(sidecar.to_string(), Box::new(e)))?;
+    s3_client
+        .put_object()
+        .bucket(&sidecar.bucket)
+        .key(&sidecar.key)
+        .content_type("application/json")
+        .body(ByteStream::from(body))
+        .send()
+        .await
+        .map_err(|e| put_error(&sidecar, e))?;
+    Ok(WriteBackStatus::Written)
+}
+
+fn put_error<E>(location: &S3Location, err: SdkError<E>) -> TypedError
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    tracing::error!(
+        err = %err,
+        bucket = %location.bucket,
+        key = %location.key,
+        "failed to write the hash back to S3"
+    );
+    s3_error(location, err, TypedError::S3Put)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(hash_width: u32, hash_height: u32) -> HashConfig {
+        HashConfig {
+            hash_width,
+            hash_height,
+            ..HashConfig::default()
+        }
+    }
+
+    #[test]
+    fn tags_only_take_hashes_that_fit_a_tag_value() {
+        let gradient = HashAlg::Gradient;
+        assert!(validate(WriteBackMode::Tags, gradient, &config(8, 8)).is_ok());
+        // 1536 bits are 192 bytes, exactly 256 base64 characters.
+        assert!(validate(WriteBackMode::Tags, gradient, &config(32, 48)).is_ok());
+        let err = validate(WriteBackMode::Tags, gradient, &config(64, 64)).unwrap_err();
+        assert_eq!(err.code(), "invalid_request");
+        assert!(validate(WriteBackMode::Sidecar, gradient, &config(64, 64)).is_ok());
+        assert!(validate(WriteBackMode::None, gradient, &config(64, 64)).is_ok());
+    }
+
+    #[test]
+    fn tags_are_sized_by_the_primary_algorithm() {
+        // a 48x48 DoubleGradient hash has only 1200 bits, 200 characters.
+        assert!(validate(
+            WriteBackMode::Tags,
+            HashAlg::DoubleGradient,
+            &config(48, 48)
+        )
+        .is_ok());
+        let err = validate(WriteBackMode::Tags, HashAlg::Gradient, &config(48, 48)).unwrap_err();
+        assert_eq!(err.code(), "invalid_request");
+    }
+}